@@ -1,9 +1,14 @@
 use anyhow::{Context, Result};
+use ed25519_dalek::pkcs8::{DecodePublicKey, EncodePrivateKey};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand::rngs::OsRng;
 use std::fs;
 use std::path::Path;
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
+
+pub mod session;
+
+pub use session::{PreKey, Session};
 
 #[derive(Debug)]
 pub struct NodeIdentity {
@@ -63,6 +68,18 @@ impl NodeIdentity {
         false
     }
 
+    /// PKCS#8 DER encoding of the signing key, used to mint TLS certificates bound to this
+    /// identity. Wiped from memory when dropped.
+    pub fn to_pkcs8_der(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let doc = self.signing_key.to_pkcs8_der().map_err(|e| anyhow::anyhow!("PKCS#8 encoding failed: {}", e))?;
+        Ok(Zeroizing::new(doc.as_bytes().to_vec()))
+    }
+
+    /// Extracts the raw Ed25519 public key from a DER SubjectPublicKeyInfo (e.g. a peer's TLS certificate)
+    pub fn public_key_from_spki(spki_der: &[u8]) -> Option<Vec<u8>> {
+        VerifyingKey::from_public_key_der(spki_der).ok().map(|key| key.to_bytes().to_vec())
+    }
+
     /// Helper for tests to verify against this identity
     pub fn verify_internal(&self, message: &[u8], signature_bytes: &[u8]) -> bool {
         Self::verify(message, signature_bytes, &self.public_key_bytes())
@@ -83,6 +100,7 @@ impl NodeIdentity {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ed25519_dalek::pkcs8::EncodePublicKey;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -101,6 +119,14 @@ mod tests {
         let sig = id.sign(b"test");
         assert!(id.verify_internal(b"test", &sig));
     }
+
+    #[test]
+    fn test_spki_roundtrip() {
+        let id = NodeIdentity::generate();
+        let spki = id.signing_key.verifying_key().to_public_key_der().unwrap();
+        assert_eq!(NodeIdentity::public_key_from_spki(spki.as_bytes()), Some(id.public_key_bytes()));
+        assert_eq!(NodeIdentity::public_key_from_spki(b"not a key"), None);
+    }
 }
 
 // - generate()          // New identity
@@ -0,0 +1,528 @@
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ed25519_dalek::VerifyingKey;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::NodeIdentity;
+
+pub const HEADER_LEN: usize = 40;
+/// Extra header bytes carried by the initiator's messages until the responder replies
+pub const INIT_LEN: usize = 64;
+/// Upper bound on message keys kept for out-of-order delivery per session, and on how far
+/// ahead of a chain a single message may be
+const MAX_SKIP: u32 = 1000;
+const X3DH_INFO: &[u8] = b"sentinel-x3dh-v1";
+const PREKEY_LABEL: &[u8] = b"sentinel-prekey-v1";
+const ROOT_INFO: &[u8] = b"sentinel-ratchet-root";
+
+type Key = [u8; 32];
+
+impl NodeIdentity {
+    /// X25519 secret derived from the Ed25519 signing key, so one `identity.key` serves both
+    /// signing and key agreement (the same mapping as libsodium's `ed25519_sk_to_curve25519`)
+    pub(crate) fn x25519_secret(&self) -> StaticSecret {
+        StaticSecret::from(self.signing_key.to_scalar_bytes())
+    }
+
+    pub fn x25519_public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.x25519_secret()).to_bytes()
+    }
+}
+
+/// Maps a peer's Ed25519 public key to its X25519 key (Edwards to Montgomery form)
+pub fn x25519_from_ed25519(public_key: &[u8]) -> Option<PublicKey> {
+    let bytes: [u8; 32] = public_key.try_into().ok()?;
+    let key = VerifyingKey::from_bytes(&bytes).ok()?;
+    Some(PublicKey::from(key.to_montgomery().to_bytes()))
+}
+
+/// Medium-term X25519 key a node publishes so others can start a session with it, like the
+/// signed prekey of X3DH. Whoever holds the secret decides how long first messages sealed
+/// to it stay readable: once it is deleted, `identity.key` alone cannot open them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreKey {
+    secret: Key,
+    /// Unix time of generation, covered by the signature
+    pub created: u64,
+}
+
+impl Drop for PreKey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl PreKey {
+    pub fn generate(created: u64) -> Self {
+        Self { secret: StaticSecret::random_from_rng(rand::rngs::OsRng).to_bytes(), created }
+    }
+
+    pub fn public_key(&self) -> Key {
+        PublicKey::from(&StaticSecret::from(self.secret)).to_bytes()
+    }
+
+    /// Signature by the owner's identity over the public key and creation time
+    pub fn sign(&self, identity: &NodeIdentity) -> Vec<u8> {
+        identity.sign(&prekey_transcript(&self.public_key(), self.created))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Prekey serialization failed")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        bincode::deserialize(bytes).context("Corrupt prekey")
+    }
+}
+
+/// Checks that the Ed25519 key `owner` signed the prekey `public` generated at `created`
+pub fn verify_prekey(owner: &[u8], public: &[u8], created: u64, signature: &[u8]) -> bool {
+    public.len() == 32 && NodeIdentity::verify(&prekey_transcript(public, created), signature, owner)
+}
+
+fn prekey_transcript(public: &[u8], created: u64) -> Vec<u8> {
+    [PREKEY_LABEL, public, &created.to_le_bytes()].concat()
+}
+
+/// What the responder needs to repeat the initiator's X3DH: the initiator's one-time key
+/// and which of the responder's prekeys it was combined with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Init {
+    pub ephemeral: Key,
+    pub prekey: Key,
+}
+
+/// Per-message header sent in the clear alongside each ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Sender's current ratchet public key
+    pub dh: Key,
+    /// Length of the sender's previous sending chain
+    pub pn: u32,
+    /// Index of this message in the current sending chain
+    pub n: u32,
+    /// Present until the initiator has heard back from the responder
+    pub init: Option<Init>,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.dh.to_vec();
+        bytes.extend_from_slice(&self.pn.to_le_bytes());
+        bytes.extend_from_slice(&self.n.to_le_bytes());
+        if let Some(init) = &self.init {
+            bytes.extend_from_slice(&init.ephemeral);
+            bytes.extend_from_slice(&init.prekey);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let init = match bytes.len() {
+            HEADER_LEN => None,
+            len if len == HEADER_LEN + INIT_LEN => Some(Init {
+                ephemeral: bytes[HEADER_LEN..HEADER_LEN + 32].try_into().ok()?,
+                prekey: bytes[HEADER_LEN + 32..].try_into().ok()?,
+            }),
+            _ => return None,
+        };
+        Some(Self {
+            dh: bytes[..32].try_into().ok()?,
+            pn: u32::from_le_bytes(bytes[32..36].try_into().ok()?),
+            n: u32::from_le_bytes(bytes[36..40].try_into().ok()?),
+            init,
+        })
+    }
+}
+
+/// Double-ratchet session with one peer, as in the Signal specification.
+///
+/// The root is seeded by X3DH: the initiator combines its identity and a one-time key with
+/// the peer's identity and signed prekey, so no chain is derived from the two identities
+/// alone. Every message is sealed under its own key, and each change of speaker mixes a
+/// fresh X25519 exchange into the root key. Once the prekey secret is deleted and the
+/// conversation has ratcheted, a leaked `identity.key` does not expose earlier messages, and a
+/// leaked session state stops working once the peer ratchets again.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    root_key: Key,
+    dh_self: Key,
+    dh_remote: Option<Key>,
+    send_chain: Option<Key>,
+    recv_chain: Option<Key>,
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    /// Keys of messages that have not arrived yet, oldest first
+    skipped: VecDeque<((Key, u32), Key)>,
+    init: Option<Init>,
+    /// Initiator's one-time key for a session we accepted, to spot a replayed first message
+    origin: Option<Key>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.dh_self.zeroize();
+        self.send_chain.zeroize();
+        self.recv_chain.zeroize();
+        self.skipped.iter_mut().for_each(|(_, key)| key.zeroize());
+    }
+}
+
+impl Session {
+    /// Starts a session with X3DH against `peer_prekey`, which the caller must have checked
+    /// with `verify_prekey`. The initiator can seal straight away; its headers carry the
+    /// `Init` the peer needs until the peer's first reply arrives.
+    pub fn initiate(identity: &NodeIdentity, peer_public_key: &[u8], peer_prekey: &[u8]) -> Result<Self> {
+        let peer = x25519_from_ed25519(peer_public_key).context("Invalid peer public key")?;
+        let prekey = PublicKey::from(Key::try_from(peer_prekey).map_err(|_| anyhow!("Invalid prekey"))?);
+        let ephemeral = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let secret = x3dh(
+            [
+                identity.x25519_secret().diffie_hellman(&prekey),
+                ephemeral.diffie_hellman(&peer),
+                ephemeral.diffie_hellman(&prekey),
+            ],
+            &identity.public_key_bytes(),
+            peer_public_key,
+        )?;
+
+        // The prekey doubles as the responder's first ratchet key
+        let ratchet = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let (root_key, send_chain) = kdf(&*secret, ratchet.diffie_hellman(&prekey).as_bytes(), ROOT_INFO);
+        Ok(Self {
+            root_key,
+            dh_self: ratchet.to_bytes(),
+            dh_remote: Some(prekey.to_bytes()),
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: VecDeque::new(),
+            init: Some(Init { ephemeral: PublicKey::from(&ephemeral).to_bytes(), prekey: prekey.to_bytes() }),
+            origin: None,
+        })
+    }
+
+    /// Accepts a session the peer started with `init`, using our prekey it named. The
+    /// session must open the message that carried `init` before it can seal.
+    pub fn respond(identity: &NodeIdentity, prekey: &PreKey, peer_public_key: &[u8], init: &Init) -> Result<Self> {
+        let peer = x25519_from_ed25519(peer_public_key).context("Invalid peer public key")?;
+        let ephemeral = PublicKey::from(init.ephemeral);
+        let own_prekey = StaticSecret::from(prekey.secret);
+        let secret = x3dh(
+            [
+                own_prekey.diffie_hellman(&peer),
+                identity.x25519_secret().diffie_hellman(&ephemeral),
+                own_prekey.diffie_hellman(&ephemeral),
+            ],
+            peer_public_key,
+            &identity.public_key_bytes(),
+        )?;
+        Ok(Self {
+            root_key: *secret,
+            dh_self: prekey.secret,
+            dh_remote: None,
+            send_chain: None,
+            recv_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: VecDeque::new(),
+            init: None,
+            origin: Some(init.ephemeral),
+        })
+    }
+
+    /// Whether this session was accepted from `init`, i.e. a message carrying it is a replay
+    pub fn accepted_from(&self, init: &Init) -> bool {
+        self.origin == Some(init.ephemeral)
+    }
+
+    /// Encrypts `plaintext` under the next message key. `aad` is authenticated but not
+    /// encrypted. Returns `(header, ciphertext)`; the session must be persisted afterwards.
+    pub fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let chain = self.send_chain.as_mut().context("Session has no sending chain")?;
+        let message_key = Zeroizing::new(step_chain(chain));
+        let header = Header {
+            dh: PublicKey::from(&StaticSecret::from(self.dh_self)).to_bytes(),
+            pn: self.previous_sent,
+            n: self.sent,
+            init: self.init,
+        };
+        self.sent += 1;
+
+        let header = header.to_bytes();
+        let ciphertext = aead(&message_key)
+            .encrypt(&Nonce::default(), Payload { msg: plaintext, aad: &[aad, &header].concat() })
+            .map_err(|_| anyhow!("Encryption failed"))?;
+        Ok((header, ciphertext))
+    }
+
+    /// Decrypts a message from the peer. The session only advances if the message
+    /// authenticates, so forged or corrupted input cannot desynchronise it.
+    pub fn open(&mut self, header: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let parsed = Header::from_bytes(header).context("Malformed ratchet header")?;
+        let mut next = self.clone();
+        let message_key = Zeroizing::new(next.message_key_for(&parsed)?);
+
+        let plaintext = aead(&message_key)
+            .decrypt(&Nonce::default(), Payload { msg: ciphertext, aad: &[aad, header].concat() })
+            .map_err(|_| anyhow!("Decryption failed"))?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Session serialization failed")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        bincode::deserialize(bytes).context("Corrupt session state")
+    }
+
+    fn message_key_for(&mut self, header: &Header) -> Result<Key> {
+        let stored = self.skipped.iter().position(|(id, _)| *id == (header.dh, header.n));
+        let new_chain = self.dh_remote != Some(header.dh);
+        if stored.is_none() {
+            // A new chain is counted from zero once the old one is finished
+            let gaps = if new_chain {
+                [header.pn.saturating_sub(self.received), header.n]
+            } else {
+                [0, header.n.saturating_sub(self.received)]
+            };
+            if gaps.iter().any(|gap| *gap > MAX_SKIP) {
+                return Err(anyhow!("Too many skipped messages"));
+            }
+        }
+
+        // Anything the peer sends proves it completed the exchange
+        self.init = None;
+        if let Some(index) = stored {
+            return Ok(self.skipped.remove(index).expect("index from position").1);
+        }
+        if new_chain {
+            self.skip_until(header.pn);
+            self.dh_ratchet(header.dh)?;
+        }
+        self.skip_until(header.n);
+
+        let chain = self.recv_chain.as_mut().context("Session has no receiving chain")?;
+        let key = step_chain(chain);
+        self.received += 1;
+        Ok(key)
+    }
+
+    /// Stores keys for messages of the current receiving chain that have not arrived yet.
+    /// Past `MAX_SKIP` stored keys the oldest are dropped, so messages lost long ago can no
+    /// longer be opened but the session keeps working.
+    fn skip_until(&mut self, until: u32) {
+        let (Some(chain), Some(remote)) = (self.recv_chain.as_mut(), self.dh_remote) else {
+            return;
+        };
+        while self.received < until {
+            self.skipped.push_back(((remote, self.received), step_chain(chain)));
+            self.received += 1;
+        }
+        while self.skipped.len() > MAX_SKIP as usize {
+            if let Some((_, mut key)) = self.skipped.pop_front() {
+                key.zeroize();
+            }
+        }
+    }
+
+    fn dh_ratchet(&mut self, remote: Key) -> Result<()> {
+        let remote_key = PublicKey::from(remote);
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.dh_remote = Some(remote);
+
+        let dh = StaticSecret::from(self.dh_self).diffie_hellman(&remote_key);
+        if !dh.was_contributory() {
+            return Err(anyhow!("Peer ratchet key is a low-order point"));
+        }
+        let (root_key, recv_chain) = kdf(&self.root_key, dh.as_bytes(), ROOT_INFO);
+        self.recv_chain = Some(recv_chain);
+
+        let ephemeral = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let dh = ephemeral.diffie_hellman(&remote_key);
+        let (root_key, send_chain) = kdf(&root_key, dh.as_bytes(), ROOT_INFO);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+        self.dh_self = ephemeral.to_bytes();
+        Ok(())
+    }
+}
+
+/// X3DH secret from the three exchanges, bound to both identities in initiator-first order
+fn x3dh(exchanges: [SharedSecret; 3], initiator: &[u8], responder: &[u8]) -> Result<Zeroizing<Key>> {
+    if exchanges.iter().any(|dh| !dh.was_contributory()) {
+        return Err(anyhow!("Key exchange with a low-order point"));
+    }
+    let input = Zeroizing::new(exchanges.iter().flat_map(|dh| *dh.as_bytes()).collect::<Vec<u8>>());
+    let mut secret = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, &input)
+        .expand(&[X3DH_INFO, initiator, responder].concat(), secret.as_mut())
+        .map_err(|_| anyhow!("Session key derivation failed"))?;
+    Ok(secret)
+}
+
+/// Root KDF: HKDF keyed by `salt`, split into the next root key and a chain key
+fn kdf(salt: &[u8], input: &[u8], info: &[u8]) -> (Key, Key) {
+    let mut okm = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(salt), input)
+        .expand(info, okm.as_mut())
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+/// Chain KDF: advances `chain` and returns the message key for the current position
+fn step_chain(chain: &mut Key) -> Key {
+    let derive = |tag: u8| -> Key {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain.as_slice()).expect("HMAC accepts any key length");
+        mac.update(&[tag]);
+        mac.finalize().into_bytes().into()
+    };
+    let message_key = derive(0x01);
+    *chain = derive(0x02);
+    message_key
+}
+
+/// Each message key encrypts exactly one message, so a fixed nonce is safe
+fn aead(message_key: &Key) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(message_key.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alice starts a session with Bob's published prekey; Bob accepts it from her first message
+    fn first_message(plaintext: &[u8]) -> (NodeIdentity, Session, NodeIdentity, PreKey, (Vec<u8>, Vec<u8>)) {
+        let (alice, bob) = (NodeIdentity::generate(), NodeIdentity::generate());
+        let prekey = PreKey::generate(1);
+        assert!(verify_prekey(&bob.public_key_bytes(), &prekey.public_key(), 1, &prekey.sign(&bob)));
+        let mut to_bob = Session::initiate(&alice, &bob.public_key_bytes(), &prekey.public_key()).unwrap();
+        let sealed = to_bob.seal(plaintext, b"aad").unwrap();
+        (alice, to_bob, bob, prekey, sealed)
+    }
+
+    fn accept(bob: &NodeIdentity, prekey: &PreKey, alice: &NodeIdentity, header: &[u8]) -> Session {
+        let init = Header::from_bytes(header).unwrap().init.unwrap();
+        Session::respond(bob, prekey, &alice.public_key_bytes(), &init).unwrap()
+    }
+
+    #[test]
+    fn test_x25519_key_matches_ed25519_mapping() {
+        let id = NodeIdentity::generate();
+        let mapped = x25519_from_ed25519(&id.public_key_bytes()).unwrap();
+        assert_eq!(mapped.to_bytes(), id.x25519_public_key());
+    }
+
+    #[test]
+    fn test_prekey_signature_binds_owner_and_age() {
+        let (owner, other) = (NodeIdentity::generate(), NodeIdentity::generate());
+        let prekey = PreKey::generate(7);
+        let signature = prekey.sign(&owner);
+        assert!(verify_prekey(&owner.public_key_bytes(), &prekey.public_key(), 7, &signature));
+        assert!(!verify_prekey(&other.public_key_bytes(), &prekey.public_key(), 7, &signature));
+        assert!(!verify_prekey(&owner.public_key_bytes(), &prekey.public_key(), 8, &signature));
+    }
+
+    #[test]
+    fn test_only_the_peer_can_open() {
+        let (alice, _, bob, prekey, (header, ciphertext)) = first_message(b"hello");
+
+        let eve = NodeIdentity::generate();
+        let mut eavesdropper = accept(&eve, &prekey, &alice, &header);
+        assert!(eavesdropper.open(&header, &ciphertext, b"aad").is_err());
+
+        let mut from_alice = accept(&bob, &prekey, &alice, &header);
+        assert!(from_alice.open(&header, &ciphertext, b"other aad").is_err());
+        assert_eq!(from_alice.open(&header, &ciphertext, b"aad").unwrap(), b"hello");
+        // Message keys are single use, and the session knows which first message made it
+        assert!(from_alice.open(&header, &ciphertext, b"aad").is_err());
+        assert!(from_alice.accepted_from(&Header::from_bytes(&header).unwrap().init.unwrap()));
+    }
+
+    #[test]
+    fn test_leaked_identity_cannot_open_stored_first_message() {
+        let (alice, _, bob, prekey, (header, ciphertext)) = first_message(b"before any reply");
+        // Bob has rotated and deleted the prekey; an attacker later copies his identity.key
+        drop(prekey);
+        let dir = tempfile::tempdir().unwrap();
+        bob.save(dir.path().join("identity.key")).unwrap();
+        let stolen = NodeIdentity::load_or_generate(dir.path().join("identity.key")).unwrap();
+        assert_eq!(stolen.public_key_bytes(), bob.public_key_bytes());
+
+        let mut attempt = accept(&stolen, &PreKey::generate(2), &alice, &header);
+        assert!(attempt.open(&header, &ciphertext, b"aad").is_err());
+    }
+
+    #[test]
+    fn test_conversation_ratchets_and_survives_reordering() {
+        let (alice, mut a, bob, prekey, first) = first_message(b"a0");
+        let second = a.seal(b"a1", b"aad").unwrap();
+        assert!(Header::from_bytes(&second.0).unwrap().init.is_some());
+
+        let mut b = accept(&bob, &prekey, &alice, &first.0);
+        assert!(b.seal(b"too early", b"").is_err());
+        assert_eq!(b.open(&second.0, &second.1, b"aad").unwrap(), b"a1");
+        assert_eq!(b.open(&first.0, &first.1, b"aad").unwrap(), b"a0");
+
+        // Replies move to fresh ratchet keys; delivery out of order still decrypts
+        let late = b.seal(b"b0", b"").unwrap();
+        let early = b.seal(b"b1", b"").unwrap();
+        assert_ne!(Header::from_bytes(&late.0).unwrap().dh, prekey.public_key());
+        assert_eq!(a.open(&early.0, &early.1, b"").unwrap(), b"b1");
+        assert_eq!(a.open(&late.0, &late.1, b"").unwrap(), b"b0");
+
+        // Once Bob has replied, Alice stops sending the X3DH parameters
+        let reply = a.seal(b"a2", b"").unwrap();
+        assert!(Header::from_bytes(&reply.0).unwrap().init.is_none());
+
+        // State round-trips through storage
+        let mut b = Session::from_bytes(&b.to_bytes()).unwrap();
+        assert_eq!(b.open(&reply.0, &reply.1, b"").unwrap(), b"a2");
+    }
+
+    #[test]
+    fn test_losses_beyond_max_skip_evict_the_oldest_keys() {
+        let (alice, mut a, bob, prekey, first) = first_message(b"a0");
+        let mut b = accept(&bob, &prekey, &alice, &first.0);
+        b.open(&first.0, &first.1, b"aad").unwrap();
+
+        // Each chain loses fewer than MAX_SKIP messages, but together they lose more
+        let mut lost = Vec::new();
+        for _ in 0..2 {
+            let sealed: Vec<_> = (0..MAX_SKIP - 400).map(|_| a.seal(b"lost", b"").unwrap()).collect();
+            let (header, ciphertext) = sealed.last().unwrap();
+            assert_eq!(b.open(header, ciphertext, b"").unwrap(), b"lost");
+            lost.extend(sealed.into_iter().rev().skip(1).rev());
+            let reply = b.seal(b"ack", b"").unwrap();
+            a.open(&reply.0, &reply.1, b"").unwrap();
+        }
+        assert!(lost.len() > MAX_SKIP as usize);
+
+        let (header, ciphertext) = a.seal(b"new", b"").unwrap();
+        assert_eq!(b.open(&header, &ciphertext, b"").unwrap(), b"new");
+        // The oldest lost messages are gone for good, the most recent ones still open
+        let (oldest, newest) = (lost.first().unwrap(), lost.last().unwrap());
+        assert!(b.open(&oldest.0, &oldest.1, b"").is_err());
+        assert_eq!(b.open(&newest.0, &newest.1, b"").unwrap(), b"lost");
+    }
+}
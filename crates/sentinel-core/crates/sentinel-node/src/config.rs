@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use sentinel_core::{NodeConfigBuilder, QueuePolicy};
+use sentinel_protocol::RelayLimits;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings read from `--config`. Everything is optional: missing values keep the engine
+/// defaults, and command-line flags override the file.
+///
+/// ```toml
+/// data_dir = "./.sentinel"
+/// listen_addrs = ["0.0.0.0:8443", "[::]:8443"]
+/// node_name = "edge-1"
+/// signaler = "127.0.0.1:8888"
+/// signaler_key = "<node ID the signaler prints at startup>"
+///
+/// [timers]
+/// heartbeat_secs = 20
+///
+/// [limits]
+/// queue_policy = "drop-newest"
+///
+/// [discovery]
+/// mdns = false
+/// stun_servers = ["127.0.0.1:3478"]
+///
+/// [relay]
+/// enabled = true
+/// bytes_per_sec = 262144
+///
+/// [tls]
+/// cert = "node.crt"
+/// key = "node.key"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub data_dir: Option<PathBuf>,
+    pub listen_addrs: Option<Vec<SocketAddr>>,
+    pub node_name: Option<String>,
+    pub signaler: Option<String>,
+    pub signaler_key: Option<String>,
+    pub timers: Timers,
+    pub limits: Limits,
+    pub discovery: Discovery,
+    pub relay: RelayConfig,
+    pub tls: Tls,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timers {
+    pub handshake_timeout_secs: Option<u64>,
+    pub heartbeat_secs: Option<u64>,
+    pub gossip_secs: Option<u64>,
+    pub autodial_secs: Option<u64>,
+    pub dht_refresh_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_missed_pongs: Option<u32>,
+    pub dedup_capacity: Option<usize>,
+    pub target_peers: Option<usize>,
+    pub queue_capacity: Option<usize>,
+    pub queue_policy: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discovery {
+    pub mdns: Option<bool>,
+    pub gossip: Option<bool>,
+    pub stun_servers: Option<Vec<String>>,
+}
+
+/// Relaying for peers that cannot reach each other. Limits left out keep the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub enabled: Option<bool>,
+    pub max_sessions: Option<usize>,
+    pub session_bytes: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Parsing {}", path.display()))
+    }
+
+    /// Applies every value present in the file on top of `builder`
+    pub fn apply(self, mut builder: NodeConfigBuilder) -> Result<NodeConfigBuilder> {
+        if let Some(addrs) = self.listen_addrs {
+            anyhow::ensure!(!addrs.is_empty(), "listen_addrs must not be empty");
+            builder = builder.listen_addrs(addrs);
+        }
+        if let Some(name) = self.node_name {
+            builder = builder.node_name(name);
+        }
+        if let Some(signaler) = self.signaler {
+            builder = builder.signaler_addr(signaler);
+        }
+        if let Some(key) = self.signaler_key {
+            builder = builder.signaler_key(key);
+        }
+
+        let secs = Duration::from_secs;
+        let timers = &self.timers;
+        for (name, value) in [
+            ("handshake_timeout_secs", timers.handshake_timeout_secs),
+            ("heartbeat_secs", timers.heartbeat_secs),
+            ("gossip_secs", timers.gossip_secs),
+            ("autodial_secs", timers.autodial_secs),
+            ("dht_refresh_secs", timers.dht_refresh_secs),
+        ] {
+            anyhow::ensure!(value != Some(0), "[timers] {} must be at least 1", name);
+        }
+        if let Some(s) = self.timers.handshake_timeout_secs {
+            builder = builder.handshake_timeout(secs(s));
+        }
+        if let Some(s) = self.timers.heartbeat_secs {
+            builder = builder.heartbeat_interval(secs(s));
+        }
+        if let Some(s) = self.timers.gossip_secs {
+            builder = builder.gossip_interval(secs(s));
+        }
+        if let Some(s) = self.timers.autodial_secs {
+            builder = builder.autodial_interval(secs(s));
+        }
+        if let Some(s) = self.timers.dht_refresh_secs {
+            builder = builder.dht_refresh_interval(secs(s));
+        }
+
+        if let Some(n) = self.limits.max_missed_pongs {
+            builder = builder.max_missed_pongs(n);
+        }
+        if let Some(n) = self.limits.dedup_capacity {
+            builder = builder.dedup_capacity(n);
+        }
+        if let Some(n) = self.limits.target_peers {
+            builder = builder.target_peers(n);
+        }
+        if let Some(n) = self.limits.queue_capacity {
+            builder = builder.queue_capacity(n);
+        }
+        if let Some(policy) = self.limits.queue_policy {
+            builder = builder.queue_policy(policy.parse::<QueuePolicy>().map_err(anyhow::Error::msg)?);
+        }
+
+        if let Some(enabled) = self.discovery.mdns {
+            builder = builder.mdns(enabled);
+        }
+        if let Some(enabled) = self.discovery.gossip {
+            builder = builder.gossip(enabled);
+        }
+        if let Some(servers) = self.discovery.stun_servers {
+            builder = builder.stun_servers(servers);
+        }
+
+        if self.relay.enabled == Some(true) {
+            let defaults = RelayLimits::default();
+            builder = builder.relay(RelayLimits {
+                max_sessions: self.relay.max_sessions.unwrap_or(defaults.max_sessions),
+                session_bytes: self.relay.session_bytes.unwrap_or(defaults.session_bytes),
+                bytes_per_sec: self.relay.bytes_per_sec.unwrap_or(defaults.bytes_per_sec),
+            });
+        }
+
+        match (self.tls.cert, self.tls.key) {
+            (Some(cert), Some(key)) => builder = builder.tls_files(cert, key),
+            (None, None) => {}
+            _ => anyhow::bail!("[tls] needs both cert and key"),
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentinel_core::NodeConfig;
+
+    fn apply(toml: &str) -> Result<NodeConfig> {
+        let file: FileConfig = toml::from_str(toml)?;
+        Ok(file.apply(NodeConfig::builder("/tmp/node"))?.build())
+    }
+
+    #[test]
+    fn test_parse_and_apply() {
+        let defaults = apply("").unwrap();
+        assert_eq!(defaults.heartbeat_interval, NodeConfig::builder("/tmp/node").build().heartbeat_interval);
+        assert!(defaults.cert_path.is_none() && defaults.relay.is_none());
+
+        let config = apply(r#"
+            node_name = "edge-1"
+            listen_addrs = ["127.0.0.1:9000"]
+            [timers]
+            heartbeat_secs = 5
+            [limits]
+            queue_policy = "drop-newest"
+            [relay]
+            enabled = true
+            max_sessions = 4
+        "#).unwrap();
+        assert_eq!(config.node_name, "edge-1");
+        assert_eq!(config.listen_addrs, ["127.0.0.1:9000".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(5));
+        assert_eq!(config.queue_policy, QueuePolicy::DropNewest);
+        assert_eq!(config.relay.map(|r| r.max_sessions), Some(4));
+
+        assert!(apply("heartbeat = 5").is_err(), "unknown keys are rejected");
+        assert!(apply("[timers]\nheartbeat_secs = 5\nbogus = 1").is_err());
+        assert!(apply("[tls]\ncert = \"node.crt\"").is_err(), "cert without key");
+        assert!(apply("[limits]\nqueue_policy = \"drop-everything\"").is_err());
+        assert!(apply("[timers]\nheartbeat_secs = 0").is_err());
+        assert!(apply("[timers]\ndht_refresh_secs = 0").is_err());
+    }
+}
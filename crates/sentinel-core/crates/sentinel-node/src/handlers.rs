@@ -1,12 +1,13 @@
 use anyhow::Result;
 use std::sync::Arc;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
 
 // Use the new library paths
-use sentinel_core::SentinelNode; 
+use sentinel_core::{dht, Delivery, SentinelEvent, SentinelNode};
 use sentinel_protocol::messages::{MessageContent, SentinelMessage, SignalingMessage};
 
-pub async fn handle_stdin(node: Arc<SentinelNode>) -> Result<()> {
+pub async fn handle_stdin(node: Arc<SentinelNode>, event_tx: mpsc::UnboundedSender<SentinelEvent>) -> Result<()> {
     let mut reader = BufReader::new(io::stdin()).lines();
 
     while let Some(line) = reader.next_line().await? {
@@ -23,37 +24,99 @@ pub async fn handle_stdin(node: Arc<SentinelNode>) -> Result<()> {
                         if target.contains('.') || target.contains(':') {
                             println!("Manual dial to address {}...", target);
                             let node_clone = Arc::clone(&node);
-                            tokio::spawn(async move {
-                                if let Err(e) = node_clone.dial_peer(target).await {
+                            let events = event_tx.clone();
+                            node.spawn_until_shutdown(async move {
+                                if let Err(e) = node_clone.dial_peer(target, None, Some(events)).await {
                                     eprintln!("Dial error: {}", e);
                                 }
                             });
                         } else {
-                            // Signal lookup (P2P Discovery)
-                            println!("Requesting lookup for Node ID: {}...", target);
-                            let lookup_msg = SentinelMessage::new_signal(
-                                node.identity.node_id(),
-                                SignalingMessage::LookupRequest { target_id: target }
-                            );
-                            let _ = node.signaler_tx.send(lookup_msg);
+                            // Mesh lookup through the DHT, with the signaler as fallback
+                            println!("Looking up Node ID: {}...", target);
+                            let node_clone = Arc::clone(&node);
+                            let events = event_tx.clone();
+                            node.spawn_until_shutdown(async move {
+                                match dht::lookup(&node_clone, &target).await {
+                                    Ok(Some(contact)) => {
+                                        println!("Found {} at {}", target, contact.address);
+                                        let addr = contact.address.to_string();
+                                        if let Err(e) = Arc::clone(&node_clone).dial_peer(addr, Some(target), Some(events)).await {
+                                            eprintln!("Dial error: {}", e);
+                                        }
+                                    }
+                                    Ok(None) | Err(_) => {
+                                        println!("{} not found in the mesh, asking the signaler...", target);
+                                        let lookup_msg = SentinelMessage::new_signal(
+                                            node_clone.identity.node_id(),
+                                            SignalingMessage::LookupRequest { target_id: target }
+                                        );
+                                        let _ = node_clone.signaler_tx.try_send(lookup_msg);
+                                    }
+                                }
+                            });
                         }
                     } else {
                         println!("Usage: /dial <address:port> OR /dial <node_id>");
                     }
                 }
+                "/msg" => {
+                    let mut args = line.splitn(3, char::is_whitespace).skip(1);
+                    match (args.next(), args.next().map(str::trim).filter(|t| !t.is_empty())) {
+                        (Some(target), Some(text)) => {
+                            let (target, text) = (target.to_string(), text.to_string());
+                            let node_clone = Arc::clone(&node);
+                            node.spawn_until_shutdown(async move {
+                                // The first message to a node needs its prekey
+                                if node_clone.peer_prekey(&target).is_none() {
+                                    let _ = dht::lookup(&node_clone, &target).await;
+                                }
+                                match node_clone.send_direct(&target, &text) {
+                                    Ok(Delivery::Direct) => println!("[YOU -> {}]: {}", target, text),
+                                    Ok(Delivery::Relayed { peers }) => {
+                                        println!("[YOU -> {} via mesh relay, {} peers]: {}", target, peers, text)
+                                    }
+                                    Err(e) => eprintln!("Message not delivered: {}", e),
+                                }
+                            });
+                        }
+                        _ => println!("Usage: /msg <node_id> <text>"),
+                    }
+                }
                 "/peers" => {
                     println!("--- Connected Peers ---");
                     if node.peers.is_empty() {
                         println!("No active peer connections.");
                     } else {
                         for entry in node.peers.iter() {
-                            println!("ADDR: {} | ID: {} | NAME: {}", 
+                            println!("ID: {} | NAME: {} | ADDR: {} ({:?})", 
                                 entry.key(), 
-                                entry.value().node_id, 
-                                entry.value().node_name
+                                entry.value().node_name,
+                                entry.value().addr,
+                                entry.value().direction
                             );
+                            if entry.value().relayed {
+                                println!("    RELAYED through {}", entry.value().addr);
+                            }
+                            match entry.value().rtt {
+                                Some(rtt) => println!("    RTT: {:?} (jitter {:?})", rtt, entry.value().jitter),
+                                None => println!("    RTT: not measured yet"),
+                            }
+                            println!("    QUEUE: {} pending, {} dropped", entry.value().queue.len(), entry.value().queue.dropped());
+                            if entry.value().addresses.len() > 1 {
+                                println!("    KNOWN ADDRS: {}", entry.value().addresses.join(", "));
+                            }
                         }
                     }
+                    println!("Address book: {} known nodes (target {} connections)", node.address_book.len(), node.config.target_peers);
+                }
+                "/relay" => {
+                    match (parts.get(1), parts.get(2)) {
+                        (Some(relay_id), Some(target)) => match node.relay_via(relay_id, target) {
+                            Ok(()) => println!("Asked {} to relay to {}; {} must ask for us too...", relay_id, target, target),
+                            Err(e) => eprintln!("Relay request not sent: {}", e),
+                        },
+                        _ => println!("Usage: /relay <relay_node_id> <target_node_id>"),
+                    }
                 }
                 "/history" => {
                     println!("--- Local Message History (Last 10) ---");
@@ -65,23 +128,24 @@ pub async fn handle_stdin(node: Arc<SentinelNode>) -> Result<()> {
                     println!("YOUR NODE ID: {}", node.identity.node_id());
                     if let Some(public) = *node.public_addr.read().await {
                         println!("PUBLIC IP: {}", public);
+                        if let Some(nat_type) = *node.nat_type.read().await {
+                            println!("NAT TYPE: {}", nat_type);
+                        }
                     } else {
                         println!("PUBLIC IP: Unknown (STUN pending or failed)");
                     }
                 }
-                _ => println!("Unknown command. Available: /dial, /peers, /history, /id"),
+                "/rotate-cert" => {
+                    match node.rotate_certificate() {
+                        Ok(()) => println!("TLS certificate rotated. New connections use the new certificate."),
+                        Err(e) => eprintln!("Certificate rotation failed: {}", e),
+                    }
+                }
+                _ => println!("Unknown command. Available: /dial, /msg, /relay, /peers, /history, /id, /rotate-cert"),
             }
         } else {
-            // Standard Chat message
-            let content = MessageContent::Chat(line.to_string());
-            let msg = SentinelMessage::new(
-                node.identity.node_id(),
-                content.clone(),
-            );
-
-            for entry in node.peers.iter() {
-                node.sign_and_send(&entry.value().tx, msg.clone());
-            }
+            // Standard Chat message, relayed across the mesh
+            node.broadcast(MessageContent::Chat(line.to_string()));
             println!("[YOU]: {}", line);
         }
     }
@@ -1,24 +1,104 @@
 use anyhow::Result;
 use clap::Parser;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
 // Imports from your clean library
-use sentinel_core::{SentinelNode, discovery, SentinelEvent};
-use sentinel_protocol::messages::{MessageContent, SentinelMessage};
+use sentinel_core::{SentinelNode, dht, discovery, NodeConfig, QueuePolicy, SentinelEvent};
+use sentinel_protocol::RelayLimits;
 
+mod config;
 mod handlers;
 
+const DEFAULT_DATA_DIR: &str = "./.sentinel";
+const DEFAULT_SIGNALER: &str = "127.0.0.1:8888";
+
+/// Flags override the `--config` file, which overrides the defaults
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value = "./.sentinel")]
-    data_dir: PathBuf,
-    #[arg(short, long, default_value_t = 8443)]
-    port: u16,
-    #[arg(short, long, default_value = "127.0.0.1:8888")]
-    signaler: String,
+    /// TOML file with node settings
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[arg(short, long)]
+    data_dir: Option<PathBuf>,
+    /// Address to listen on; repeat for several. Defaults to IPv4 and IPv6 wildcards.
+    #[arg(short, long)]
+    listen: Vec<SocketAddr>,
+    /// Port for every listen address
+    #[arg(short, long)]
+    port: Option<u16>,
+    #[arg(short, long)]
+    signaler: Option<String>,
+    /// Node ID the signaler must prove in TLS, as it prints at startup. Without it, the
+    /// key shown on the first connection is pinned.
+    #[arg(long)]
+    signaler_key: Option<String>,
+    /// STUN server for public address and NAT detection; repeat for several
+    #[arg(long)]
+    stun: Vec<String>,
+    /// Name announced to peers
+    #[arg(long)]
+    name: Option<String>,
+    /// Connections to keep open by auto-dialing known peers
+    #[arg(long)]
+    target_peers: Option<usize>,
+    /// Messages queued per peer before the queue policy applies
+    #[arg(long)]
+    queue_capacity: Option<usize>,
+    /// drop-oldest, drop-newest or disconnect
+    #[arg(long)]
+    queue_policy: Option<QueuePolicy>,
+    /// Relay traffic for peers that cannot reach each other, with the default limits
+    /// unless the config file sets them
+    #[arg(long)]
+    relay: bool,
+}
+
+fn node_config(args: Args) -> Result<NodeConfig> {
+    let file = match &args.config {
+        Some(path) => config::FileConfig::load(path)?,
+        None => config::FileConfig::default(),
+    };
+    let data_dir = args.data_dir.or(file.data_dir.clone()).unwrap_or_else(|| DEFAULT_DATA_DIR.into());
+    let mut builder = NodeConfig::builder(data_dir).signaler_addr(DEFAULT_SIGNALER);
+    builder = file.apply(builder)?;
+
+    if !args.listen.is_empty() {
+        builder = builder.listen_addrs(args.listen);
+    }
+    if let Some(port) = args.port {
+        builder = builder.listen_port(port);
+    }
+    if let Some(signaler) = args.signaler {
+        builder = builder.signaler_addr(signaler);
+    }
+    if let Some(key) = args.signaler_key {
+        builder = builder.signaler_key(key);
+    }
+    if !args.stun.is_empty() {
+        builder = builder.stun_servers(args.stun);
+    }
+    if let Some(name) = args.name {
+        builder = builder.node_name(name);
+    }
+    if let Some(count) = args.target_peers {
+        builder = builder.target_peers(count);
+    }
+    if let Some(capacity) = args.queue_capacity {
+        builder = builder.queue_capacity(capacity);
+    }
+    if let Some(policy) = args.queue_policy {
+        builder = builder.queue_policy(policy);
+    }
+    let mut config = builder.build();
+    if args.relay && config.relay.is_none() {
+        config.relay = Some(RelayLimits::default());
+    }
+    Ok(config)
 }
 
 #[tokio::main]
@@ -27,25 +107,33 @@ async fn main() -> Result<()> {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    let args = Args::parse();
+    let config = node_config(Args::parse())?;
 
     // 1. Initialize Engine
-    let (node_struct, signaler_rx) = SentinelNode::new(args.data_dir, args.port).await?;
+    let (node_struct, signaler_rx) = SentinelNode::with_config(config).await?;
     let node = Arc::new(node_struct);
     let (event_tx, mut event_rx) = mpsc::unbounded_channel();
 
     // 2. Start Discovery & Engine (The Engine now owns the TcpListener!)
-    discovery::start_discovery(Arc::clone(&node), args.port).await?;
+    discovery::start_discovery(Arc::clone(&node)).await?;
     
-    let engine_node = Arc::clone(&node);
-    tokio::spawn(engine_node.run(event_tx));
+    node.spawn(Arc::clone(&node).run(event_tx.clone()));
 
-    // 3. Start Background Services
-    let sig_node = Arc::clone(&node);
-    let sig_addr = args.signaler.clone();
-    tokio::spawn(async move { sig_node.start_signaler_client(sig_addr, signaler_rx).await; });
-    tokio::spawn(Arc::clone(&node).start_gossip_service());
-    tokio::spawn(Arc::clone(&node).start_heartbeat_service());
+    // 3. Start Background Services (tracked by the node, so shutdown waits for them)
+    node.spawn(Arc::clone(&node).start_signaler_client(signaler_rx));
+    node.spawn(Arc::clone(&node).start_gossip_service());
+    node.spawn(Arc::clone(&node).start_heartbeat_service());
+    node.spawn(Arc::clone(&node).start_autodial_service());
+    node.spawn(dht::start_refresh_service(Arc::clone(&node)));
+    if !node.config.stun_servers.is_empty() {
+        let stun_node = Arc::clone(&node);
+        node.spawn(async move {
+            match stun_node.discover_and_set_public_ip().await {
+                Ok(report) => println!("[SYSTEM] Public address {} behind {} NAT", report.public_addr, report.nat_type),
+                Err(e) => println!("[SYSTEM] {:#}", e),
+            }
+        });
+    }
 
     // 4. Event UI Loop (Prints messages from the Engine)
     tokio::spawn(async move {
@@ -54,13 +142,39 @@ async fn main() -> Result<()> {
                 SentinelEvent::ChatMessage { sender, text } => {
                     println!("\n[{}] {}", sender, text);
                 }
+                SentinelEvent::DirectMessage { sender, text } => {
+                    println!("\n[DM {}] {}", sender, text);
+                }
+                SentinelEvent::DirectMessageFailed { sender, error } => {
+                    println!("[SYSTEM] Unreadable direct message from {}: {}", sender, error);
+                }
                 SentinelEvent::SystemLog(msg) => {
                     println!("[SYSTEM] {}", msg);
                 }
-                SentinelEvent::PeerConnected { peer_id, .. } => {
-                    println!("[+] Connected to: {}", peer_id);
+                SentinelEvent::Listening { addr } => {
+                    println!("[SYSTEM] Engine active on {}", addr);
+                }
+                SentinelEvent::PeerConnected { peer_id, addr, direction } => {
+                    println!("[+] Connected to: {} ({:?}, {})", peer_id, direction, addr);
+                }
+                SentinelEvent::HandshakeFailed { addr, direction, error } => {
+                    println!("[SYSTEM] {:?} handshake with {} failed: {}", direction, addr, error);
+                }
+                SentinelEvent::PunchSucceeded { peer_id, addr } => {
+                    println!("[SYSTEM] Punched through to {} at {}", peer_id, addr);
+                }
+                SentinelEvent::PunchFailed { peer_id, addr, error } => {
+                    println!("[SYSTEM] Hole punch to {} at {} failed: {}", peer_id, addr, error);
+                }
+                SentinelEvent::Relayed { peer_id, relay } => {
+                    println!("[SYSTEM] Reaching {} through the relay at {}", peer_id, relay);
+                }
+                SentinelEvent::RelayFailed { peer_id, relay, error } => {
+                    println!("[SYSTEM] Relay to {} through {} failed: {}", peer_id, relay, error);
+                }
+                SentinelEvent::PeerDisconnected { peer_id, reason } => {
+                    println!("[-] Disconnected from {}: {}", peer_id, reason);
                 }
-                _ => {}
             }
         }
     });
@@ -70,7 +184,7 @@ async fn main() -> Result<()> {
 
     // 5. Input & Shutdown Logic
     tokio::select! {
-        res = handlers::handle_stdin(Arc::clone(&node)) => {
+        res = handlers::handle_stdin(Arc::clone(&node), event_tx) => {
             if let Err(e) = res { eprintln!("Terminal error: {}", e); }
         }
         _ = tokio::signal::ctrl_c() => {
@@ -79,14 +193,9 @@ async fn main() -> Result<()> {
     }
 
     // Graceful Shutdown
-    let goodbye = SentinelMessage::new(
-        node.identity.node_id(), 
-        MessageContent::Disconnect("Node shutting down".into())
-    );
-    for entry in node.peers.iter() {
-        let _ = entry.value().tx.send(goodbye.clone());
+    if let Err(e) = node.shutdown(Duration::from_secs(5)).await {
+        eprintln!("Shutdown incomplete: {}", e);
     }
-    let _ = node.db.flush_async().await;
-    
+
     Ok(())
 }
\ No newline at end of file
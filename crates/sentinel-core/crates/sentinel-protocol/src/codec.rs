@@ -15,6 +15,12 @@ impl SentinelCodec {
     }
 }
 
+impl Default for SentinelCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Decoder for SentinelCodec {
     type Item = SentinelMessage;
     type Error = ProtocolError;
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod commands;
 
 pub use self::commands::CommandHandler;
\ No newline at end of file
@@ -26,4 +26,10 @@ pub enum ProtocolError {
     #[error("Protocol serialization error: {0}")]
     SerializationError(String),
 
+    #[error("Malformed STUN message: {0}")]
+    MalformedStun(&'static str),
+
+    #[error("Relay refused: {0}")]
+    RelayRefused(&'static str),
+
 }
\ No newline at end of file
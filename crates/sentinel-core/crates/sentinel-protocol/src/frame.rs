@@ -51,7 +51,7 @@ impl Frame {
             return Ok(None);
         }
 
-        if &src[0..MAGIC_LEN] != MAGIC {
+        if src[0..MAGIC_LEN] != MAGIC {
             return Err(ProtocolError::InvalidMagic);
         }
 
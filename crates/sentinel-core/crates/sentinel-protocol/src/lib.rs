@@ -3,8 +3,12 @@ pub mod codec;
 pub mod commands;
 pub mod error;
 pub mod messages;
+pub mod relay;
+pub mod stun;
 
 pub use frame::Frame;
 pub use codec::SentinelCodec;
 pub use error::ProtocolError;
-pub use messages::{MessageContent, SentinelMessage, SignalingMessage, PeerInfo};
\ No newline at end of file
+pub use messages::{MessageContent, SentinelMessage, SignalingMessage, PeerInfo, SignedPreKey};
+pub use relay::{Relay, RelayLimits};
+pub use stun::StunMessage;
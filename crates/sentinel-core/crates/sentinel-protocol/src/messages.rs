@@ -8,10 +8,23 @@ pub struct PeerInfo {
     pub address: SocketAddr,
     pub node_name: String,
     pub last_seen: u64,
+    /// The node's current prekey, if the reporter knows it
+    pub prekey: Option<SignedPreKey>,
+}
+
+/// X25519 key a node publishes for starting end-to-end sessions with it, signed by its
+/// identity over `key` and `created`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SignedPreKey {
+    pub key: Vec<u8>,
+    pub created: u64,
+    pub signature: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum SignalingMessage {
+    /// Claims a directory entry. Sent first with an empty `signature`; after the signaler's
+    /// `Challenge` it is sent again, signed over `registration_transcript`.
     Register {
         node_id: String,
         public_key: Vec<u8>,
@@ -24,26 +37,120 @@ pub enum SignalingMessage {
         peer_id: String,
         public_addr: SocketAddr,
     },
+    /// Sent by the signaler to both ends of a lookup: connect to `peer_id` at `target_addr`
+    /// when the clock reaches `timestamp_ns` (nanoseconds since the Unix epoch)
     PunchCommand {
+        peer_id: String,
         target_addr: SocketAddr,
         timestamp_ns: u64,
     },
     Error(String),
+    /// Withdraws a registration before the node disconnects
+    Unregister {
+        node_id: String,
+    },
+    /// Asks for a relayed path to `target_id` after hole punching failed
+    RelayRequest {
+        target_id: String,
+    },
+    /// One end's share of a relay session with `peer_id`: connect to the relay (the
+    /// signaler itself, or the peer that sent the offer) and bind with `token`
+    RelayOffer {
+        peer_id: String,
+        token: Vec<u8>,
+    },
+    /// First frame on a connection to a relay; the rest of the stream is spliced
+    RelayBind {
+        token: Vec<u8>,
+    },
+    /// The signaler's fresh nonce, which a registering node must sign to prove its key
+    Challenge {
+        nonce: Vec<u8>,
+    },
 }
 
+const REGISTRATION_LABEL: &[u8] = b"sentinel-register-v1";
+
+impl SignalingMessage {
+    /// Bytes a node signs to register: the claimed node ID and the signaler's nonce
+    pub fn registration_transcript(node_id: &str, nonce: &[u8]) -> Vec<u8> {
+        let mut data = REGISTRATION_LABEL.to_vec();
+        data.extend_from_slice(node_id.as_bytes());
+        data.extend_from_slice(nonce);
+        data
+    }
+}
+
+/// Hop budget for relayed messages, enough to cross a sparse mesh
+pub const DEFAULT_TTL: u8 = 8;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MessageContent {
     Chat(String),
     Handshake { 
         public_key: Vec<u8>,
-        node_name: String 
+        node_name: String,
+        /// Fresh 32-byte challenge the remote side must sign
+        nonce: Vec<u8>,
+        /// Port this node accepts connections on, so inbound peers can be dialed back
+        listen_port: u16,
+        prekey: SignedPreKey,
+    },
+    /// Signature over both handshake nonces and the TLS channel binding
+    HandshakeProof {
+        signature: Vec<u8>,
     },
     PeerDiscovery(Vec<PeerInfo>),
+    /// Text addressed to a single node, identified by its hex node ID. The text is sealed
+    /// end-to-end for the recipient, so forwarding nodes cannot read it.
+    Direct {
+        recipient: String,
+        /// Double-ratchet header: sender ratchet key, previous chain length, message index,
+        /// followed by the X3DH parameters until the recipient has replied
+        header: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+    /// Kademlia FIND_NODE: asks for the contacts closest to `target`
+    FindNode {
+        target: String,
+    },
+    /// Kademlia FIND_VALUE on a node's contact record: answered with `Value` when the
+    /// target is known, otherwise with `Nodes`
+    FindValue {
+        target: String,
+    },
+    /// Reply carrying the closest known contacts, tied to the request's message ID
+    Nodes {
+        request_id: Uuid,
+        nodes: Vec<PeerInfo>,
+    },
+    /// Reply carrying the contact record of the looked-up node
+    Value {
+        request_id: Uuid,
+        node: PeerInfo,
+    },
     Signal(SignalingMessage),
-    Ping,
-    Pong,
+    /// Keepalive; the peer must echo `nonce` in a `Pong`
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
     /// New for Phase 3: System-level notifications
     Disconnect(String), 
+    /// Asks a peer running a relay to splice us with `target`, which must be its peer too.
+    /// The relay answers both ends with `Signal(RelayOffer)`.
+    RelayRequest {
+        target: String,
+    },
+}
+
+impl MessageContent {
+    /// Content that is flooded across the mesh and may arrive from a node other than its author
+    pub fn is_relayable(&self) -> bool {
+        matches!(self, MessageContent::Chat(_) | MessageContent::Direct { .. })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -55,12 +162,14 @@ pub struct SentinelMessage {
     pub timestamp: u64,     
     pub content: MessageContent,
     pub signature: Vec<u8>,
+    /// Hops left before relays stop forwarding. Not signed, so each relay can decrement it.
+    pub ttl: u8,
 }
 
 impl SentinelMessage {
     pub fn new(sender: String, content: MessageContent) -> Self {
         Self {
-            version: 3,     // Current Phase
+            version: 4,     // Current Phase
             id: Uuid::new_v4(),
             sender,
             public_key: vec![],
@@ -70,6 +179,7 @@ impl SentinelMessage {
                 .as_secs(),
             content,
             signature: vec![],
+            ttl: DEFAULT_TTL,
         }
     }
 
@@ -93,4 +203,18 @@ impl SentinelMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
         bincode::deserialize(bytes)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_outside_signed_bytes() {
+        let mut msg = SentinelMessage::new("node".into(), MessageContent::Chat("hi".into()));
+        let signed = msg.sig_hash();
+        msg.ttl -= 1;
+        assert_eq!(msg.sig_hash(), signed);
+        assert_eq!(SentinelMessage::from_bytes(&msg.to_bytes()).unwrap().ttl, DEFAULT_TTL - 1);
+    }
+}
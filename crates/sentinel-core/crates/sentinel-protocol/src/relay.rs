@@ -0,0 +1,238 @@
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+use crate::error::ProtocolError;
+
+/// How long an allocated session waits for both ends to bind
+pub const RELAY_BIND_TIMEOUT: Duration = Duration::from_secs(15);
+const CHUNK_SIZE: usize = 16 * 1024;
+
+pub type RelayToken = [u8; 16];
+
+/// What a relay is willing to carry. The relay only ever sees TLS records, so these are
+/// the sole knobs it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayLimits {
+    /// Sessions allocated or running at once
+    pub max_sessions: usize,
+    /// Bytes one session may carry, both directions together, before it is cut
+    pub session_bytes: u64,
+    /// Bandwidth shared by all sessions of the relay, in bytes per second; 0 is unlimited
+    pub bytes_per_sec: u64,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self { max_sessions: 32, session_bytes: 64 * 1024 * 1024, bytes_per_sec: 1024 * 1024 }
+    }
+}
+
+struct Slot {
+    partner: RelayToken,
+    allocated: Instant,
+    /// Set once this end has bound; the partner hands its stream over through it
+    waiting: Option<oneshot::Sender<(TcpStream, BytesMut)>>,
+}
+
+/// Splices two TCP connections that present the tokens of one allocation. Each end gets
+/// its own token over its authenticated signaling (or peer) connection, so holding one
+/// proves who is binding. The nodes run TLS end to end through the splice, pinned to
+/// each other's node keys, so the relay can neither read nor impersonate either side.
+pub struct Relay {
+    limits: RelayLimits,
+    pending: Mutex<HashMap<RelayToken, Slot>>,
+    active: AtomicUsize,
+    bandwidth: Mutex<Bandwidth>,
+}
+
+impl Relay {
+    pub fn new(limits: RelayLimits) -> Self {
+        Self {
+            limits,
+            pending: Mutex::new(HashMap::new()),
+            active: AtomicUsize::new(0),
+            bandwidth: Mutex::new(Bandwidth { available: limits.bytes_per_sec as f64, last: Instant::now() }),
+        }
+    }
+
+    pub fn limits(&self) -> RelayLimits {
+        self.limits
+    }
+
+    /// Sessions currently spliced
+    pub fn active_sessions(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Reserves a session, returning one token for each end; None when the relay is full
+    pub fn allocate(&self) -> Option<(RelayToken, RelayToken)> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, slot| slot.waiting.is_some() || slot.allocated.elapsed() < RELAY_BIND_TIMEOUT);
+        if self.active_sessions() + pending.len() / 2 >= self.limits.max_sessions {
+            return None;
+        }
+        let (a, b) = (random_token(), random_token());
+        let allocated = Instant::now();
+        pending.insert(a, Slot { partner: b, allocated, waiting: None });
+        pending.insert(b, Slot { partner: a, allocated, waiting: None });
+        Some((a, b))
+    }
+
+    /// Binds `stream` to the session of `token`. `buffered` holds bytes that arrived along
+    /// with the bind request and belong to the spliced stream. The first end waits here
+    /// for its partner and then carries the session until either side closes or the
+    /// session quota runs out; the second end returns at once.
+    pub async fn join(&self, token: &[u8], stream: TcpStream, buffered: BytesMut) -> Result<(), ProtocolError> {
+        let token: RelayToken = token.try_into().map_err(|_| ProtocolError::RelayRefused("malformed token"))?;
+        let partner_bound = {
+            let mut pending = self.pending.lock().unwrap();
+            let slot = pending.get(&token).ok_or(ProtocolError::RelayRefused("unknown or expired token"))?;
+            if slot.waiting.is_some() {
+                return Err(ProtocolError::RelayRefused("token already bound"));
+            }
+            let partner = slot.partner;
+            match pending.get_mut(&partner).and_then(|p| p.waiting.take()) {
+                Some(waiter) => {
+                    pending.remove(&token);
+                    pending.remove(&partner);
+                    Err(waiter)
+                }
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    pending.get_mut(&token).expect("slot checked above").waiting = Some(tx);
+                    Ok((rx, partner))
+                }
+            }
+        };
+        let (bound, partner) = match partner_bound {
+            Ok(waiting) => waiting,
+            Err(waiter) => {
+                return waiter.send((stream, buffered)).map_err(|_| ProtocolError::RelayRefused("partner gave up"));
+            }
+        };
+
+        let other = match tokio::time::timeout(RELAY_BIND_TIMEOUT, bound).await {
+            Ok(Ok(other)) => other,
+            _ => {
+                let mut pending = self.pending.lock().unwrap();
+                pending.remove(&token);
+                pending.remove(&partner);
+                return Err(ProtocolError::RelayRefused("partner never bound"));
+            }
+        };
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let spliced = self.splice((stream, buffered), other).await;
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        spliced.map_err(ProtocolError::Io)
+    }
+
+    async fn splice(&self, a: (TcpStream, BytesMut), b: (TcpStream, BytesMut)) -> io::Result<()> {
+        let ((mut a, a_early), (mut b, b_early)) = (a, b);
+        let budget = AtomicU64::new(self.limits.session_bytes);
+        self.charge(&budget, a_early.len() + b_early.len()).await?;
+        b.write_all(&a_early).await?;
+        a.write_all(&b_early).await?;
+
+        let (a_read, a_write) = a.split();
+        let (b_read, b_write) = b.split();
+        // Either direction ending takes the whole session down
+        tokio::select! {
+            done = self.pump(a_read, b_write, &budget) => done,
+            done = self.pump(b_read, a_write, &budget) => done,
+        }
+    }
+
+    async fn pump(&self, mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin, budget: &AtomicU64) -> io::Result<()> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = from.read(&mut buf).await?;
+            if n == 0 {
+                return to.shutdown().await;
+            }
+            self.charge(budget, n).await?;
+            to.write_all(&buf[..n]).await?;
+        }
+    }
+
+    /// Takes `n` bytes from the session quota and waits until the relay's bandwidth allows them
+    async fn charge(&self, budget: &AtomicU64, n: usize) -> io::Result<()> {
+        budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(n as u64))
+            .map_err(|_| io::Error::other("relay session quota exhausted"))?;
+        let wait = self.bandwidth.lock().unwrap().take(n as f64, self.limits.bytes_per_sec as f64);
+        tokio::time::sleep(wait).await;
+        Ok(())
+    }
+}
+
+/// Token bucket holding up to one second of traffic. Taking more than is available
+/// leaves a debt that the caller sleeps off.
+struct Bandwidth {
+    available: f64,
+    last: Instant,
+}
+
+impl Bandwidth {
+    fn take(&mut self, n: f64, rate: f64) -> Duration {
+        let now = Instant::now();
+        self.available = (self.available + now.duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+        self.available -= n;
+        if self.available >= 0.0 || rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / rate)
+        }
+    }
+}
+
+fn random_token() -> RelayToken {
+    *uuid::Uuid::new_v4().as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let (client, server) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_splices_bound_pair_within_quota() {
+        let relay = std::sync::Arc::new(Relay::new(RelayLimits { max_sessions: 1, session_bytes: 10, bytes_per_sec: 1024 }));
+        let (token_a, token_b) = relay.allocate().unwrap();
+        assert!(relay.allocate().is_none(), "one session fills the relay");
+        assert!(relay.join(&[0; 16], pair(&TcpListener::bind("127.0.0.1:0").await.unwrap()).await.1, BytesMut::new()).await.is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut a, a_relay) = pair(&listener).await;
+        let (mut b, b_relay) = pair(&listener).await;
+        let join = |token: RelayToken, stream, early: &'static [u8]| {
+            let relay = relay.clone();
+            tokio::spawn(async move { relay.join(&token, stream, BytesMut::from(early)).await })
+        };
+        let (first, second) = (join(token_a, a_relay, b"early"), join(token_b, b_relay, b""));
+
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"early");
+        b.write_all(b"hello").await.unwrap();
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        // The quota of 10 bytes is spent, so the next byte ends the session
+        a.write_all(b"!").await.unwrap();
+        assert_eq!(b.read(&mut buf).await.unwrap_or(0), 0);
+        // Whichever end bound first carried the session, and saw it cut
+        assert!(first.await.unwrap().is_err() || second.await.unwrap().is_err());
+    }
+}
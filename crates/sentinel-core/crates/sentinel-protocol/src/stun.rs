@@ -0,0 +1,226 @@
+use crate::error::ProtocolError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_SIZE: usize = 20;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802C;
+
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+pub type TransactionId = [u8; 12];
+
+/// The part of STUN (RFC 5389) needed to learn a public address, plus the CHANGE-REQUEST
+/// and OTHER-ADDRESS attributes of RFC 5780 used to classify NAT filtering
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StunMessage {
+    /// Asks the server for our address as it sees it. The change flags ask it to answer
+    /// from its other IP and/or port instead.
+    BindingRequest {
+        transaction_id: TransactionId,
+        change_ip: bool,
+        change_port: bool,
+    },
+    BindingResponse {
+        transaction_id: TransactionId,
+        mapped_addr: SocketAddr,
+        /// Where the server answers changed requests from, if it can
+        other_addr: Option<SocketAddr>,
+    },
+}
+
+impl StunMessage {
+    /// A request with a fresh random transaction ID
+    pub fn binding_request(change_ip: bool, change_port: bool) -> Self {
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..12]);
+        Self::BindingRequest { transaction_id, change_ip, change_port }
+    }
+
+    pub fn transaction_id(&self) -> &TransactionId {
+        match self {
+            Self::BindingRequest { transaction_id, .. } | Self::BindingResponse { transaction_id, .. } => transaction_id,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut attributes = Vec::new();
+        let kind = match self {
+            Self::BindingRequest { change_ip, change_port, .. } => {
+                if *change_ip || *change_port {
+                    let flags = if *change_ip { CHANGE_IP } else { 0 } | if *change_port { CHANGE_PORT } else { 0 };
+                    push_attribute(&mut attributes, ATTR_CHANGE_REQUEST, &flags.to_be_bytes());
+                }
+                BINDING_REQUEST
+            }
+            Self::BindingResponse { transaction_id, mapped_addr, other_addr } => {
+                push_attribute(&mut attributes, ATTR_XOR_MAPPED_ADDRESS, &encode_address(*mapped_addr, Some(transaction_id)));
+                if let Some(other) = other_addr {
+                    push_attribute(&mut attributes, ATTR_OTHER_ADDRESS, &encode_address(*other, None));
+                }
+                BINDING_RESPONSE
+            }
+        };
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + attributes.len());
+        buf.extend_from_slice(&kind.to_be_bytes());
+        buf.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(self.transaction_id());
+        buf.extend_from_slice(&attributes);
+        buf
+    }
+
+    /// Parses a Binding request or success response. Attributes this module does not use,
+    /// such as SOFTWARE or FINGERPRINT, are skipped.
+    pub fn decode(buf: &[u8]) -> Result<Self, ProtocolError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(ProtocolError::Incomplete);
+        }
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if buf[4..8] != MAGIC_COOKIE.to_be_bytes() {
+            return Err(ProtocolError::InvalidMagic);
+        }
+        if !length.is_multiple_of(4) || buf.len() < HEADER_SIZE + length {
+            return Err(ProtocolError::MalformedStun("bad message length"));
+        }
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..HEADER_SIZE]);
+
+        let (mut change, mut mapped, mut xor_mapped, mut other_addr) = (0u32, None, None, None);
+        let mut rest = &buf[HEADER_SIZE..HEADER_SIZE + length];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(ProtocolError::MalformedStun("truncated attribute header"));
+            }
+            let attr = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let padded = (len + 3) & !3;
+            if rest.len() < 4 + padded {
+                return Err(ProtocolError::MalformedStun("truncated attribute"));
+            }
+            let value = &rest[4..4 + len];
+            match attr {
+                ATTR_CHANGE_REQUEST if len == 4 => change = u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                ATTR_MAPPED_ADDRESS => mapped = Some(decode_address(value, None)?),
+                ATTR_XOR_MAPPED_ADDRESS => xor_mapped = Some(decode_address(value, Some(&transaction_id))?),
+                ATTR_OTHER_ADDRESS => other_addr = Some(decode_address(value, None)?),
+                _ => {}
+            }
+            rest = &rest[4 + padded..];
+        }
+
+        match kind {
+            BINDING_REQUEST => Ok(Self::BindingRequest {
+                transaction_id,
+                change_ip: change & CHANGE_IP != 0,
+                change_port: change & CHANGE_PORT != 0,
+            }),
+            BINDING_RESPONSE => Ok(Self::BindingResponse {
+                transaction_id,
+                // Old servers only send the plain attribute
+                mapped_addr: xor_mapped.or(mapped).ok_or(ProtocolError::MalformedStun("no mapped address"))?,
+                other_addr,
+            }),
+            _ => Err(ProtocolError::MalformedStun("not a binding request or success response")),
+        }
+    }
+}
+
+fn push_attribute(buf: &mut Vec<u8>, attr: u16, value: &[u8]) {
+    buf.extend_from_slice(&attr.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+/// The address attribute layout, XORed with the cookie and transaction ID when given one
+fn encode_address(addr: SocketAddr, xor: Option<&TransactionId>) -> Vec<u8> {
+    let mask = xor_mask(xor);
+    let port = addr.port() ^ if xor.is_some() { (MAGIC_COOKIE >> 16) as u16 } else { 0 };
+    let (family, ip) = match addr.ip() {
+        IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(ip.iter().zip(mask.iter()).map(|(b, m)| b ^ m));
+    value
+}
+
+fn decode_address(value: &[u8], xor: Option<&TransactionId>) -> Result<SocketAddr, ProtocolError> {
+    let mask = xor_mask(xor);
+    let ip_len = match value.get(1) {
+        Some(&FAMILY_IPV4) => 4,
+        Some(&FAMILY_IPV6) => 16,
+        _ => return Err(ProtocolError::MalformedStun("unknown address family")),
+    };
+    if value.len() != 4 + ip_len {
+        return Err(ProtocolError::MalformedStun("bad address length"));
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ if xor.is_some() { (MAGIC_COOKIE >> 16) as u16 } else { 0 };
+    let octets: Vec<u8> = value[4..].iter().zip(mask.iter()).map(|(b, m)| b ^ m).collect();
+    let ip = match ip_len {
+        4 => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+        _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).expect("16 octets"))),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Cookie followed by the transaction ID, or zeros for the plain address attributes
+fn xor_mask(xor: Option<&TransactionId>) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    if let Some(transaction_id) = xor {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decodes_rfc5769_response_vector() {
+        let vector: [u8; 80] = [
+            0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+            0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+            0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+            0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9,
+            0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+        ];
+        match StunMessage::decode(&vector).unwrap() {
+            StunMessage::BindingResponse { mapped_addr, other_addr, .. } => {
+                assert_eq!(mapped_addr, "192.0.2.1:32853".parse().unwrap());
+                assert_eq!(other_addr, None);
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let request = StunMessage::binding_request(false, true);
+        assert_eq!(StunMessage::decode(&request.encode()).unwrap(), request);
+
+        let response = StunMessage::BindingResponse {
+            transaction_id: *request.transaction_id(),
+            mapped_addr: "[2001:db8::1]:40000".parse().unwrap(),
+            other_addr: Some("198.51.100.7:3479".parse().unwrap()),
+        };
+        assert_eq!(StunMessage::decode(&response.encode()).unwrap(), response);
+        assert!(StunMessage::decode(&response.encode()[..HEADER_SIZE + 4]).is_err());
+    }
+}
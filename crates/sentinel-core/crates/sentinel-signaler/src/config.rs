@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Settings read from `--config`. Everything is optional: missing values keep the
+/// defaults, and command-line flags override the file.
+///
+/// ```toml
+/// listen = "0.0.0.0:8888"
+/// data_dir = "./.signaler"
+/// stun = "0.0.0.0:3478"
+///
+/// [limits]
+/// max_nodes = 5000
+/// handshake_timeout_secs = 5
+///
+/// [relay]
+/// enabled = true
+/// bytes_per_sec = 4194304
+///
+/// [tls]
+/// cert = "signaler.crt"
+/// key = "signaler.key"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub listen: Option<SocketAddr>,
+    pub data_dir: Option<PathBuf>,
+    pub stun: Option<SocketAddr>,
+    pub limits: Limits,
+    pub relay: RelayConfig,
+    pub tls: Tls,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_nodes: Option<usize>,
+    pub handshake_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub enabled: Option<bool>,
+    pub max_sessions: Option<usize>,
+    pub session_bytes: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Parsing {}", path.display()))
+    }
+}
@@ -1,62 +1,251 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use clap::Parser;
 use dashmap::DashMap;
 use futures::{SinkExt, StreamExt};
-use sentinel_protocol::{MessageContent, SentinelCodec, SentinelMessage, SignalingMessage};
+use sentinel_crypto::NodeIdentity;
+use sentinel_protocol::frame::{MAGIC, MAGIC_LEN};
+use sentinel_protocol::{MessageContent, Relay, RelayLimits, SentinelCodec, SentinelMessage, SignalingMessage, StunMessage};
+use sentinel_transport::tls_config::{load_certs, load_or_generate_node_cert, load_private_key};
+use sentinel_transport::verifier::peer_public_key;
+use sentinel_transport::{SentinelAcceptor, TlsTransport};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
 use tokio_util::codec::Framed;
 
-type PeerDirectory = Arc<DashMap<String, SocketAddr>>;
+mod config;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:8888";
+const DEFAULT_DATA_DIR: &str = "./.signaler";
+const DEFAULT_MAX_NODES: usize = 10_000;
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time between a lookup and the punch it triggers, for the commands to reach both ends
+const PUNCH_LEAD: Duration = Duration::from_millis(500);
+/// Messages waiting to be pushed to a registered node, such as punch commands
+const OUTBOX_CAPACITY: usize = 16;
+const NONCE_LEN: usize = 32;
+
+struct Registration {
+    /// Where the node's connection comes from, i.e. its public NAT mapping
+    addr: SocketAddr,
+    outbox: mpsc::Sender<SentinelMessage>,
+}
+
+type NodeStream = Framed<TlsTransport<TcpStream>, SentinelCodec>;
+
+/// State shared by every connection
+struct Signaler {
+    directory: DashMap<String, Registration>,
+    relay: Option<Relay>,
+    acceptor: SentinelAcceptor,
+    /// Registrations held at once; further nodes are refused
+    max_nodes: usize,
+    /// Held across the capacity check and the insert it guards
+    admission: Mutex<()>,
+    /// Limit for the TLS handshake and for answering the registration challenge
+    handshake_timeout: Duration,
+}
+
+/// Flags override the `--config` file, which overrides the defaults
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// TOML file with signaler settings
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// TCP address for signaling and relaying
+    #[arg(short, long)]
+    listen: Option<SocketAddr>,
+    /// Holds the signaler's identity key and the TLS certificate minted for it
+    #[arg(short, long)]
+    data_dir: Option<PathBuf>,
+    /// PEM certificate to serve instead of the minted one; it must carry the identity key
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// Registrations held at once
+    #[arg(long)]
+    max_nodes: Option<usize>,
+    /// Seconds allowed for the TLS handshake and the registration challenge
+    #[arg(long)]
+    handshake_timeout: Option<u64>,
+    /// Also answer STUN Binding requests on this UDP address, e.g. 0.0.0.0:3478. The next
+    /// port up serves as the alternate address for NAT filtering tests.
+    #[arg(long)]
+    stun: Option<SocketAddr>,
+    /// Run only the STUN responder, without signaling
+    #[arg(long)]
+    stun_only: bool,
+    /// Refuse to relay traffic for nodes that cannot punch through to each other
+    #[arg(long)]
+    no_relay: bool,
+    /// Relay sessions allocated or running at once
+    #[arg(long)]
+    relay_sessions: Option<usize>,
+    /// Bytes one relay session may carry before it is cut
+    #[arg(long)]
+    relay_session_bytes: Option<u64>,
+    /// Bandwidth shared by all relay sessions in bytes per second, 0 for unlimited
+    #[arg(long)]
+    relay_bytes_per_sec: Option<u64>,
+}
+
+/// Loads the identity and its certificate and merges flags over the config file
+fn signaler(args: &Args, file: config::FileConfig) -> Result<Signaler> {
+    let data_dir = args.data_dir.clone().or(file.data_dir).unwrap_or_else(|| DEFAULT_DATA_DIR.into());
+    std::fs::create_dir_all(&data_dir)?;
+    let identity = NodeIdentity::load_or_generate(data_dir.join("identity.key"))?;
+    let (certs, key) = match (args.cert.clone().or(file.tls.cert), args.key.clone().or(file.tls.key)) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = load_certs(&cert_path).with_context(|| format!("Reading {}", cert_path.display()))?;
+            let key = load_private_key(&key_path).with_context(|| format!("Reading {}", key_path.display()))?;
+            let bound = certs.first().and_then(peer_public_key) == Some(identity.public_key_bytes());
+            anyhow::ensure!(bound, "Certificate {} does not carry the signaler identity key", cert_path.display());
+            (certs, key)
+        }
+        (None, None) => load_or_generate_node_cert(&identity, &data_dir)?,
+        _ => anyhow::bail!("[tls] needs both cert and key"),
+    };
+    let handshake_timeout = args.handshake_timeout.or(file.limits.handshake_timeout_secs)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+
+    let relay_enabled = !args.no_relay && file.relay.enabled != Some(false);
+    let defaults = RelayLimits::default();
+    let relay = relay_enabled.then(|| Relay::new(RelayLimits {
+        max_sessions: args.relay_sessions.or(file.relay.max_sessions).unwrap_or(defaults.max_sessions),
+        session_bytes: args.relay_session_bytes.or(file.relay.session_bytes).unwrap_or(defaults.session_bytes),
+        bytes_per_sec: args.relay_bytes_per_sec.or(file.relay.bytes_per_sec).unwrap_or(defaults.bytes_per_sec),
+    }));
+
+    println!("Signaler key {} (pin it with the nodes' --signaler-key)", identity.node_id());
+    Ok(Signaler {
+        directory: DashMap::new(),
+        relay,
+        acceptor: SentinelAcceptor::new(certs, key, handshake_timeout)?,
+        max_nodes: args.max_nodes.or(file.limits.max_nodes).unwrap_or(DEFAULT_MAX_NODES),
+        admission: Mutex::new(()),
+        handshake_timeout,
+    })
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    
-    let addr = "0.0.0.0:8888";
+    let args = Args::parse();
+    let file = match &args.config {
+        Some(path) => config::FileConfig::load(path)?,
+        None => config::FileConfig::default(),
+    };
+    let stun = args.stun.or(file.stun);
+    anyhow::ensure!(!args.stun_only || stun.is_some(), "--stun-only needs a STUN address");
+
+    if let Some(stun_addr) = stun {
+        if args.stun_only {
+            return serve_stun(stun_addr).await;
+        }
+        tokio::spawn(async move {
+            if let Err(e) = serve_stun(stun_addr).await {
+                eprintln!("STUN responder failed: {:?}", e);
+            }
+        });
+    }
+
+    let addr = args.listen.or(file.listen).unwrap_or_else(|| DEFAULT_LISTEN.parse().expect("valid default address"));
+    let signaler = Arc::new(signaler(&args, file)?);
     let listener = TcpListener::bind(addr).await?;
-    let directory: PeerDirectory = Arc::new(DashMap::new());
 
-    println!("SENTINEL SIGNALER live on {}", addr);
+    println!("SENTINEL SIGNALER live on {}", listener.local_addr()?);
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let directory_ref = Arc::clone(&directory);
+        let signaler_ref = Arc::clone(&signaler);
 
         tokio::spawn(async move {
-            if let Err(e) = handle_signaling_node(directory_ref, socket, peer_addr).await {
+            if let Err(e) = handle_connection(signaler_ref, socket, peer_addr).await {
                 eprintln!("Signaler error for {}: {:?}", peer_addr, e);
             }
         });
     }
 }
 
+/// Relay binds stay plain TCP, since the nodes run their own TLS through the splice;
+/// everything else must be a node completing mutual TLS
+async fn handle_connection(signaler: Arc<Signaler>, socket: TcpStream, peer_addr: SocketAddr) -> Result<()> {
+    if let Some(relay) = &signaler.relay {
+        let mut magic = [0u8; MAGIC_LEN];
+        let peeked = tokio::time::timeout(signaler.handshake_timeout, socket.peek(&mut magic)).await;
+        if matches!(peeked, Ok(Ok(MAGIC_LEN))) && magic == MAGIC {
+            let mut framed = Framed::new(socket, SentinelCodec::new());
+            let first = tokio::time::timeout(signaler.handshake_timeout, framed.next()).await
+                .context("Relay bind timed out")?
+                .context("Connection closed before the relay bind")??;
+            let MessageContent::Signal(SignalingMessage::RelayBind { token }) = first.content else {
+                anyhow::bail!("Expected a relay bind");
+            };
+            let parts = framed.into_parts();
+            relay.join(&token, parts.io, parts.read_buf).await?;
+            return Ok(());
+        }
+    }
+    let tls = signaler.acceptor.accept(socket).await?;
+    let tls_key = tls.peer_public_key().context("Node presented no certificate")?;
+    handle_signaling_node(&signaler, Framed::new(tls, SentinelCodec::new()), peer_addr, &tls_key).await
+}
+
 async fn handle_signaling_node(
-    dir: PeerDirectory,
-    socket: TcpStream,
+    signaler: &Signaler,
+    mut framed: NodeStream,
     peer_addr: SocketAddr,
+    tls_key: &[u8],
 ) -> Result<()> {
-    let mut framed = Framed::new(socket, SentinelCodec::new());
+    let dir = &signaler.directory;
 
-    if let Some(Ok(msg)) = framed.next().await {
-        if let MessageContent::Signal(SignalingMessage::Register { node_id, .. }) = msg.content {
+    // A node that completed TLS but never registers must not hold its task forever
+    let first = tokio::time::timeout(signaler.handshake_timeout, framed.next()).await
+        .context("Registration timed out")?;
+    if let Some(Ok(msg)) = first {
+        if let MessageContent::Signal(SignalingMessage::Register { node_id, public_key, .. }) = msg.content {
+            let (outbox, mut inbox) = mpsc::channel(OUTBOX_CAPACITY);
+            let registration = Registration { addr: peer_addr, outbox: outbox.clone() };
+            let admitted = authenticate(signaler, &mut framed, &node_id, &public_key, tls_key).await
+                .and_then(|()| signaler.register(&node_id, registration));
+            if let Err(e) = admitted {
+                let refusal = SignalingMessage::Error(format!("Registration refused: {}", e));
+                let _ = framed.send(SentinelMessage::new_signal(node_id, refusal)).await;
+                return Err(e);
+            }
             println!("Node {} registered from {}", node_id, peer_addr);
-            
-            dir.insert(node_id.clone(), peer_addr);
-            
-            while let Some(result) = framed.next().await {
-                match result {
-                    Ok(client_msg) => {
-                        process_signal(&dir, &mut framed, client_msg, &node_id).await?;
-                    }
-                    Err(e) => {
-                        eprintln!("Connection lost for {}: {}", node_id, e);
-                        break;
+
+            loop {
+                tokio::select! {
+                    Some(pushed) = inbox.recv() => {
+                        if framed.send(pushed).await.is_err() { break; }
                     }
+                    result = framed.next() => match result {
+                        Some(Ok(SentinelMessage { content: MessageContent::Signal(SignalingMessage::Unregister { .. }), .. })) | None => break,
+                        Some(Ok(client_msg)) => {
+                            if let Err(e) = process_signal(signaler, &mut framed, client_msg, &node_id, peer_addr).await {
+                                eprintln!("Signaler error for {}: {:?}", node_id, e);
+                                break;
+                            }
+                        }
+                        Some(Err(e)) => {
+                            eprintln!("Connection lost for {}: {}", node_id, e);
+                            break;
+                        }
+                    },
                 }
             }
 
-            dir.remove(&node_id);
+            // A newer registration of the same node keeps its entry
+            dir.remove_if(&node_id, |_, r| r.outbox.same_channel(&outbox));
             println!("Node {} deregistered", node_id);
         }
     }
@@ -64,24 +253,79 @@ async fn handle_signaling_node(
     Ok(())
 }
 
+impl Signaler {
+    /// Adds an authenticated node to the directory. The capacity is checked again here,
+    /// since concurrent registrations may all have passed the check before their challenge.
+    fn register(&self, node_id: &str, registration: Registration) -> Result<()> {
+        let _admission = self.admission.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = &self.directory;
+        anyhow::ensure!(dir.contains_key(node_id) || dir.len() < self.max_nodes, "directory is full");
+        dir.insert(node_id.to_string(), registration);
+        Ok(())
+    }
+}
+
+/// Checks that `node_id` names `public_key`, the key of the node's TLS certificate, and
+/// that the node holds the matching private key, by having it sign a fresh challenge.
+/// The ID is derived from the key, so a live registration can only be replaced by the
+/// same key.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    signaler: &Signaler,
+    framed: &mut Framed<S, SentinelCodec>,
+    node_id: &str,
+    public_key: &[u8],
+    tls_key: &[u8],
+) -> Result<()> {
+    anyhow::ensure!(node_id == hex::encode(public_key), "node ID does not match its key");
+    anyhow::ensure!(public_key == tls_key, "key differs from the TLS certificate");
+    let dir = &signaler.directory;
+    anyhow::ensure!(dir.contains_key(node_id) || dir.len() < signaler.max_nodes, "directory is full");
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    framed.send(SentinelMessage::new_signal(node_id.to_string(), SignalingMessage::Challenge { nonce: nonce.to_vec() })).await?;
+
+    let answer = tokio::time::timeout(signaler.handshake_timeout, framed.next()).await
+        .context("challenge timed out")?
+        .context("connection closed during registration")??;
+    let MessageContent::Signal(SignalingMessage::Register { node_id: answered_id, public_key: answered_key, signature }) = answer.content else {
+        anyhow::bail!("expected a signed registration");
+    };
+    anyhow::ensure!(answered_id == node_id && answered_key == public_key, "signed registration differs from the claim");
+    let transcript = SignalingMessage::registration_transcript(node_id, &nonce);
+    anyhow::ensure!(NodeIdentity::verify(&transcript, &signature, public_key), "bad signature");
+    Ok(())
+}
+
 async fn process_signal(
-    dir: &PeerDirectory,
-    framed: &mut Framed<TcpStream, SentinelCodec>,
+    signaler: &Signaler,
+    framed: &mut NodeStream,
     msg: SentinelMessage,
     sender_id: &str,
+    sender_addr: SocketAddr,
 ) -> Result<()> {
+    let (dir, relay) = (&signaler.directory, signaler.relay.as_ref());
     if let MessageContent::Signal(signal) = msg.content {
         match signal {
             SignalingMessage::LookupRequest { target_id } => {
-                if let Some(target_addr) = dir.get(&target_id) {
-                    let response = SentinelMessage::new_signal(
-                        sender_id.to_string(),
-                        SignalingMessage::PeerResponse {
-                            peer_id: target_id,
-                            public_addr: *target_addr,
+                let target = dir.get(&target_id).map(|r| (r.addr, r.outbox.clone()));
+                if let Some((target_addr, target_outbox)) = target {
+                    // Both ends connect towards each other at the same instant
+                    let timestamp_ns = (SystemTime::now().duration_since(UNIX_EPOCH)? + PUNCH_LEAD).as_nanos() as u64;
+                    let to_target = SentinelMessage::new_signal(
+                        target_id.clone(),
+                        SignalingMessage::PunchCommand {
+                            peer_id: sender_id.to_string(),
+                            target_addr: sender_addr,
+                            timestamp_ns,
                         },
                     );
-                    framed.send(response).await?;
+                    // Without the target's side a punch cannot work, so a busy target
+                    // only gets dialed the plain way
+                    let signal = if target_outbox.try_send(to_target).is_ok() {
+                        SignalingMessage::PunchCommand { peer_id: target_id, target_addr, timestamp_ns }
+                    } else {
+                        SignalingMessage::PeerResponse { peer_id: target_id, public_addr: target_addr }
+                    };
+                    framed.send(SentinelMessage::new_signal(sender_id.to_string(), signal)).await?;
                 } else {
                     let err = SentinelMessage::new_signal(
                         sender_id.to_string(),
@@ -90,8 +334,165 @@ async fn process_signal(
                     framed.send(err).await?;
                 }
             }
+            SignalingMessage::RelayRequest { target_id } => {
+                let target_outbox = dir.get(&target_id).map(|r| r.outbox.clone());
+                let signal = match (target_outbox, relay) {
+                    (None, _) => SignalingMessage::Error("Peer not found".to_string()),
+                    (Some(_), None) => SignalingMessage::Error("Relaying is disabled".to_string()),
+                    (Some(target_outbox), Some(relay)) => match relay.allocate() {
+                        None => SignalingMessage::Error("Relay is full".to_string()),
+                        Some((ours, theirs)) => {
+                            let to_target = SentinelMessage::new_signal(
+                                target_id.clone(),
+                                SignalingMessage::RelayOffer { peer_id: sender_id.to_string(), token: theirs.to_vec() },
+                            );
+                            // The allocation expires on its own if the target never binds
+                            if target_outbox.try_send(to_target).is_ok() {
+                                println!("Relaying {} <-> {}", sender_id, target_id);
+                                SignalingMessage::RelayOffer { peer_id: target_id, token: ours.to_vec() }
+                            } else {
+                                SignalingMessage::Error("Peer is busy".to_string())
+                            }
+                        }
+                    },
+                };
+                framed.send(SentinelMessage::new_signal(sender_id.to_string(), signal)).await?;
+            }
             _ => println!("Signal not yet implemented: {:?}", signal),
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// A minimal STUN server (RFC 5389 Binding only), so nodes can learn their public address
+/// without outside servers. It has a single IP, so requests to change the IP or the port
+/// are both answered from the alternate port.
+async fn serve_stun(addr: SocketAddr) -> Result<()> {
+    let alternate_port = addr.port().checked_add(1).context("No port left for the alternate STUN address")?;
+    let primary = Arc::new(UdpSocket::bind(addr).await?);
+    let alternate = Arc::new(UdpSocket::bind(SocketAddr::new(addr.ip(), alternate_port)).await?);
+    let (primary_addr, alternate_addr) = (primary.local_addr()?, alternate.local_addr()?);
+    println!("STUN responder live on {} (alternate {})", primary_addr, alternate_addr);
+
+    tokio::spawn(answer_stun(Arc::clone(&alternate), Arc::clone(&primary), alternate_addr));
+    answer_stun(primary, alternate, alternate_addr).await
+}
+
+/// Answers requests arriving on `socket`, sending those that ask for a changed source
+/// through `other`
+async fn answer_stun(socket: Arc<UdpSocket>, other: Arc<UdpSocket>, alternate_addr: SocketAddr) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Ok(StunMessage::BindingRequest { transaction_id, change_ip, change_port }) = StunMessage::decode(&buf[..len]) else {
+            continue;
+        };
+        let response = StunMessage::BindingResponse { transaction_id, mapped_addr: from, other_addr: Some(alternate_addr) };
+        let via = if change_ip || change_port { &other } else { &socket };
+        let _ = via.send_to(&response.encode(), from).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentinel_transport::tls_config::SENTINEL_SERVER_NAME;
+    use sentinel_transport::SentinelConnector;
+
+    fn test_signaler(dir: &tempfile::TempDir, flags: &[&str]) -> Signaler {
+        let args = Args::parse_from(["sentinel-signaler"].iter().chain(flags));
+        let file = config::FileConfig { data_dir: Some(dir.path().to_path_buf()), ..Default::default() };
+        signaler(&args, file).unwrap()
+    }
+
+    /// Runs `authenticate` for a claim of `(node_id, public_key)` over a TLS session proving
+    /// `tls_key`. The client answers the challenge with whatever `answer` builds from the nonce.
+    async fn challenge(
+        signaler: &Signaler,
+        node_id: &str,
+        public_key: &[u8],
+        tls_key: &[u8],
+        answer: impl FnOnce(&[u8]) -> SignalingMessage + Send + 'static,
+    ) -> Result<()> {
+        let (server, client) = tokio::io::duplex(4096);
+        let mut client = Framed::new(client, SentinelCodec::new());
+        tokio::spawn(async move {
+            if let Some(Ok(SentinelMessage { content: MessageContent::Signal(SignalingMessage::Challenge { nonce }), .. })) = client.next().await {
+                let _ = client.send(SentinelMessage::new_signal(String::new(), answer(&nonce))).await;
+            }
+        });
+        authenticate(signaler, &mut Framed::new(server, SentinelCodec::new()), node_id, public_key, tls_key).await
+    }
+
+    fn signed(node: &NodeIdentity, nonce: &[u8]) -> SignalingMessage {
+        let signature = node.sign(&SignalingMessage::registration_transcript(&node.node_id(), nonce));
+        SignalingMessage::Register { node_id: node.node_id(), public_key: node.public_key_bytes(), signature }
+    }
+
+    #[tokio::test]
+    async fn test_registration_challenge() {
+        let dir = tempfile::tempdir().unwrap();
+        let signaler = test_signaler(&dir, &[]);
+        let (node, other) = (Arc::new(NodeIdentity::generate()), NodeIdentity::generate());
+        let (id, key) = (node.node_id(), node.public_key_bytes());
+
+        let answering = Arc::clone(&node);
+        assert!(challenge(&signaler, &id, &key, &key, move |nonce| signed(&answering, nonce)).await.is_ok());
+
+        // Signed over a nonce other than the one issued
+        let answering = Arc::clone(&node);
+        let err = challenge(&signaler, &id, &key, &key, move |_| signed(&answering, &[0; NONCE_LEN])).await.unwrap_err();
+        assert_eq!(err.to_string(), "bad signature");
+
+        // Another node's key replayed in the answer, or in the claim over our own TLS session
+        let err = challenge(&signaler, &id, &key, &key, move |nonce| signed(&other, nonce)).await.unwrap_err();
+        assert_eq!(err.to_string(), "signed registration differs from the claim");
+        let other_key = NodeIdentity::generate().public_key_bytes();
+        let err = challenge(&signaler, &hex::encode(&other_key), &other_key, &key, |_| unreachable!()).await.unwrap_err();
+        assert_eq!(err.to_string(), "key differs from the TLS certificate");
+
+        // An ID that is not the hex of its key
+        let err = challenge(&signaler, &"00".repeat(32), &key, &key, |_| unreachable!()).await.unwrap_err();
+        assert_eq!(err.to_string(), "node ID does not match its key");
+    }
+
+    #[test]
+    fn test_register_rechecks_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let signaler = test_signaler(&dir, &["--max-nodes", "1"]);
+        let entry = || Registration { addr: SocketAddr::from(([127, 0, 0, 1], 1)), outbox: mpsc::channel(1).0 };
+        assert!(signaler.register("a", entry()).is_ok());
+        // The node that already holds the slot may re-register; a second node may not
+        assert!(signaler.register("a", entry()).is_ok());
+        assert!(signaler.register("b", entry()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_pin_and_silent_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let signaler = Arc::new(test_signaler(&dir, &["--handshake-timeout", "1"]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, peer_addr)) = listener.accept().await {
+                tokio::spawn(handle_connection(Arc::clone(&signaler), socket, peer_addr));
+            }
+        });
+
+        let node_dir = tempfile::tempdir().unwrap();
+        let (certs, key) = load_or_generate_node_cert(&NodeIdentity::generate(), node_dir.path()).unwrap();
+        let connector = SentinelConnector::new(certs, key);
+        let connect = |pin: Vec<u8>| async {
+            connector.connect(SENTINEL_SERVER_NAME, TcpStream::connect(addr).await.unwrap(), Some(pin)).await
+        };
+
+        // A node pinning another key refuses the signaler's certificate
+        assert!(connect(NodeIdentity::generate().public_key_bytes()).await.is_err());
+
+        // With the right pin TLS completes, but a node that never registers is cut off
+        let signaler_key = NodeIdentity::load_or_generate(dir.path().join("identity.key")).unwrap().public_key_bytes();
+        let mut framed = Framed::new(connect(signaler_key).await.unwrap(), SentinelCodec::new());
+        let closed = tokio::time::timeout(Duration::from_secs(5), framed.next()).await.unwrap();
+        assert!(!matches!(closed, Some(Ok(_))));
+    }
+}
@@ -1,43 +1,59 @@
-use std::sync::Arc;
-use std::path::Path;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
 use crate::tls::TlsTransport;
-use crate::tls_config::{load_certs, load_private_key};
+use crate::tls_config::crypto_provider;
+use crate::verifier::NodeCertVerifier;
 use crate::error::{TransportError, TransportResult};
 
 #[derive(Clone)]
 pub struct SentinelAcceptor {
-    inner: TlsAcceptor,
+    inner: Arc<RwLock<TlsAcceptor>>,
     handshake_timeout: Duration,
 }
 
 impl SentinelAcceptor {
+    /// Mutual TLS: every inbound peer must present a self-signed Ed25519 node certificate
     pub fn new(
-        cert_path: &Path,
-        key_path: &Path,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
         handshake_timeout: Duration,
     ) -> anyhow::Result<Self> {
-        let certs = load_certs(cert_path)?;
-        let key = load_private_key(key_path)?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Self::build(certs, key)?)),
+            handshake_timeout,
+        })
+    }
+
+    /// Swaps the served certificate; connections already accepted keep the old one
+    pub fn set_certificate(
+        &self,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> anyhow::Result<()> {
+        let acceptor = Self::build(certs, key)?;
+        *self.inner.write().unwrap_or_else(|e| e.into_inner()) = acceptor;
+        Ok(())
+    }
 
-        let mut config = ServerConfig::builder()
-            .with_no_client_auth()
+    fn build(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> anyhow::Result<TlsAcceptor> {
+        let mut config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(NodeCertVerifier::new(None)))
             .with_single_cert(certs, key)?;
 
         config.alpn_protocols = vec![b"sentinel-v1".to_vec()];
 
-        Ok(Self {
-            inner: TlsAcceptor::from(Arc::new(config)),
-            handshake_timeout,
-        })
+        Ok(TlsAcceptor::from(Arc::new(config)))
     }
 
     pub async fn accept(&self, stream: TcpStream) -> TransportResult<TlsTransport<TcpStream>> {
-        let handshake_future = self.inner.accept(stream);
+        let acceptor = self.inner.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let handshake_future = acceptor.accept(stream);
         
         match tokio::time::timeout(self.handshake_timeout, handshake_future).await {
             Ok(result) => {
@@ -47,4 +63,4 @@ impl SentinelAcceptor {
             Err(_) => Err(TransportError::HandshakeTimeout),
         }
     }
-}
\ No newline at end of file
+}
@@ -1,78 +1,56 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::net::TcpStream;
-use tokio_rustls::{TlsConnector, client::TlsStream};
-use rustls::{ClientConfig, pki_types::ServerName};
+use tokio_rustls::TlsConnector;
+use rustls::{ClientConfig, pki_types::{CertificateDer, PrivateKeyDer, ServerName}};
 use anyhow::Result;
 
-//  Custom Verifier
-#[derive(Debug)]
-struct DangerVerifier;
-
-impl rustls::client::danger::ServerCertVerifier for DangerVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &rustls::pki_types::CertificateDer<'_>, 
-        _intermediates: &[rustls::pki_types::CertificateDer<'_>], 
-        _server_name: &ServerName,
-        _ocsp_response: &[u8],
-        _now: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
-    }
-
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &rustls::pki_types::CertificateDer<'_>, 
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
-
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &rustls::pki_types::CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
-
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        use rustls::SignatureScheme::*;
-        vec![
-            ECDSA_NISTP256_SHA256,
-            ECDSA_NISTP384_SHA384,
-            RSA_PSS_SHA256,
-            RSA_PSS_SHA384,
-            RSA_PSS_SHA512,
-            ED25519, 
-        ]
-    }
-}
+use crate::tls::TlsTransport;
+use crate::tls_config::crypto_provider;
+use crate::verifier::NodeCertVerifier;
 
 // connector 
 pub struct SentinelConnector {
-    config: Arc<ClientConfig>,
+    identity: RwLock<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
 }
 
 impl SentinelConnector {
-    pub fn new() -> Self {
-        let config = ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(Arc::new(DangerVerifier))
-            .with_no_client_auth();
+    /// `certs`/`key` are this node's own identity certificate, presented for mutual TLS
+    pub fn new(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Self {
+        Self { identity: RwLock::new((certs, key)) }
+    }
 
-        Self { config: Arc::new(config) }
+    /// Swaps the client certificate used for future connections
+    pub fn set_certificate(&self, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) {
+        *self.identity.write().unwrap_or_else(|e| e.into_inner()) = (certs, key);
     }
 
-    pub async fn connect(&self, domain: &str, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
-        let connector = TlsConnector::from(self.config.clone());
+    /// Connects and verifies the remote certificate. When `expected_key` is given the
+    /// handshake fails unless the peer proves ownership of exactly that Ed25519 key.
+    pub async fn connect(
+        &self,
+        domain: &str,
+        stream: TcpStream,
+        expected_key: Option<Vec<u8>>,
+    ) -> Result<TlsTransport<TcpStream>> {
+        let (certs, key) = {
+            let identity = self.identity.read().unwrap_or_else(|e| e.into_inner());
+            (identity.0.clone(), identity.1.clone_key())
+        };
+
+        let mut config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NodeCertVerifier::new(expected_key)))
+            .with_client_auth_cert(certs, key)?;
+
+        config.alpn_protocols = vec![b"sentinel-v1".to_vec()];
+
+        let connector = TlsConnector::from(Arc::new(config));
         let server_name = ServerName::try_from(domain.to_string())
             .map_err(|_| anyhow::anyhow!("Invalid DNS Name"))?
             .to_owned();
 
         let tls_stream = connector.connect(server_name, stream).await?;
-        Ok(tls_stream)
+        Ok(TlsTransport::new(tls_stream.into()))
     }
-}
\ No newline at end of file
+}
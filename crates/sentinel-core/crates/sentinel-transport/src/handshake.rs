@@ -0,0 +1,225 @@
+use anyhow::{anyhow, bail, Result};
+use sentinel_crypto::session::verify_prekey;
+use sentinel_crypto::NodeIdentity;
+use sentinel_protocol::{MessageContent, SentinelMessage, SignedPreKey};
+use std::time::Duration;
+
+use crate::state::{Authenticated, Connection, SentinelTransport, Unauthenticated};
+
+const NONCE_LEN: usize = 32;
+const TRANSCRIPT_LABEL: &[u8] = b"sentinel-handshake-v1";
+
+/// Connection states from the protocol spec. CLOSED is simply the connection being dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    /// TLS established, waiting for the peer's `Handshake`
+    Pending,
+    /// `Handshake` received, waiting for the peer's `HandshakeProof`
+    Verifying,
+    /// Both proofs checked; the peer may be registered
+    Established,
+}
+
+/// What the local node announces in its `Handshake`
+pub struct Hello<'a> {
+    pub identity: &'a NodeIdentity,
+    pub node_name: &'a str,
+    pub listen_port: u16,
+    pub prekey: SignedPreKey,
+}
+
+/// Bytes a peer signs to prove liveness: its own nonce, the verifier's nonce and the TLS
+/// exporter. The verifier's fresh nonce defeats replay; the exporter ties the proof to
+/// this exact TLS session.
+pub fn transcript(signer_nonce: &[u8], verifier_nonce: &[u8], channel_binding: &[u8]) -> Vec<u8> {
+    let mut data = TRANSCRIPT_LABEL.to_vec();
+    data.extend_from_slice(signer_nonce);
+    data.extend_from_slice(verifier_nonce);
+    data.extend_from_slice(channel_binding);
+    data
+}
+
+/// A valid signature by a key the sender ID is derived from
+pub fn verify_signature(msg: &SentinelMessage) -> Result<()> {
+    if !NodeIdentity::verify(&msg.sig_hash(), &msg.signature, &msg.public_key) {
+        bail!("Missing or invalid signature on message {}", msg.id);
+    }
+    if msg.sender != hex::encode(&msg.public_key) {
+        bail!("Sender {} does not match signing key", msg.sender);
+    }
+    Ok(())
+}
+
+/// Strict envelope check: a valid signature, a sender ID derived from the signing key,
+/// and a signing key equal to the one pinned for this connection at the TLS handshake.
+pub fn verify_envelope(msg: &SentinelMessage, pinned_key: &[u8]) -> Result<()> {
+    verify_signature(msg)?;
+    if msg.public_key != pinned_key {
+        bail!("Signing key does not match the key pinned for this connection");
+    }
+    Ok(())
+}
+
+/// Runs the mutual challenge-response handshake over a freshly established TLS stream.
+/// `pinned_key` is the Ed25519 key proven by the peer's TLS certificate. This is the only
+/// way to turn an `Unauthenticated` connection into an `Authenticated` one.
+pub async fn perform<T: SentinelTransport>(
+    mut conn: Connection<T, Unauthenticated>,
+    hello: Hello<'_>,
+    pinned_key: &[u8],
+    channel_binding: &[u8],
+    timeout: Duration,
+) -> Result<Connection<T, Authenticated>> {
+    let auth = tokio::time::timeout(timeout, exchange(&mut conn, hello, pinned_key, channel_binding))
+        .await
+        .map_err(|_| anyhow!("Handshake timed out"))??;
+    Ok(conn.into_authenticated(auth))
+}
+
+async fn exchange<T: SentinelTransport>(
+    conn: &mut Connection<T, Unauthenticated>,
+    hello: Hello<'_>,
+    pinned_key: &[u8],
+    channel_binding: &[u8],
+) -> Result<Authenticated> {
+    let mut state = HandshakeState::Pending;
+    let local_nonce: [u8; NONCE_LEN] = rand::random();
+    let identity = hello.identity;
+
+    let hello_msg = SentinelMessage::new(identity.node_id(), MessageContent::Handshake {
+        public_key: identity.public_key_bytes(),
+        node_name: hello.node_name.to_string(),
+        nonce: local_nonce.to_vec(),
+        listen_port: hello.listen_port,
+        prekey: hello.prekey,
+    });
+    conn.send_message(sign(identity, hello_msg)).await?;
+
+    let msg = next_verified(conn, pinned_key, state).await?;
+    let (peer_name, remote_nonce, listen_port, prekey) = match msg.content {
+        MessageContent::Handshake { public_key, node_name, nonce, listen_port, prekey }
+            if public_key == pinned_key && nonce.len() == NONCE_LEN
+                && verify_prekey(pinned_key, &prekey.key, prekey.created, &prekey.signature) =>
+        {
+            (node_name, nonce, listen_port, prekey)
+        }
+        _ => bail!("Invalid handshake in {:?} state", state),
+    };
+    advance(&mut state, HandshakeState::Verifying, &msg.sender);
+
+    let proof = identity.sign(&transcript(&local_nonce, &remote_nonce, channel_binding));
+    let proof_msg = SentinelMessage::new(identity.node_id(), MessageContent::HandshakeProof { signature: proof });
+    conn.send_message(sign(identity, proof_msg)).await?;
+
+    let expected = transcript(&remote_nonce, &local_nonce, channel_binding);
+    match next_verified(conn, pinned_key, state).await?.content {
+        MessageContent::HandshakeProof { signature } if NodeIdentity::verify(&expected, &signature, pinned_key) => {}
+        _ => bail!("Invalid handshake proof in {:?} state", state),
+    }
+    advance(&mut state, HandshakeState::Established, &msg.sender);
+
+    Ok(Authenticated {
+        user_id: msg.sender,
+        node_name: peer_name,
+        public_key: pinned_key.to_vec(),
+        listen_port,
+        prekey,
+    })
+}
+
+fn sign(identity: &NodeIdentity, mut msg: SentinelMessage) -> SentinelMessage {
+    msg.public_key = identity.public_key_bytes();
+    msg.signature = identity.sign(&msg.sig_hash());
+    msg
+}
+
+async fn next_verified<T: SentinelTransport>(
+    conn: &mut Connection<T, Unauthenticated>,
+    pinned_key: &[u8],
+    state: HandshakeState,
+) -> Result<SentinelMessage> {
+    let msg = conn
+        .next_message()
+        .await?
+        .ok_or_else(|| anyhow!("Connection closed in {:?} state", state))?;
+    verify_envelope(&msg, pinned_key)?;
+    Ok(msg)
+}
+
+fn advance(state: &mut HandshakeState, next: HandshakeState, peer: &str) {
+    tracing::debug!("Handshake with {}: {:?} -> {:?}", peer, state, next);
+    *state = next;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentinel_crypto::PreKey;
+    use sentinel_protocol::SentinelCodec;
+    use tokio_util::codec::Framed;
+
+    fn hello(identity: &NodeIdentity) -> Hello<'_> {
+        let prekey = PreKey::generate(0);
+        let (created, signature) = (prekey.created, prekey.sign(identity));
+        Hello {
+            identity,
+            node_name: "test",
+            listen_port: 1,
+            prekey: SignedPreKey { key: prekey.public_key().to_vec(), created, signature },
+        }
+    }
+
+    #[test]
+    fn test_proof_bound_to_nonces_and_channel() {
+        let signer = NodeIdentity::generate();
+        let (ours, theirs, binding) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let proof = signer.sign(&transcript(&ours, &theirs, &binding));
+        let key = signer.public_key_bytes();
+
+        assert!(NodeIdentity::verify(&transcript(&ours, &theirs, &binding), &proof, &key));
+        // Replayed against a fresh challenge or another TLS session, the proof is useless
+        assert!(!NodeIdentity::verify(&transcript(&ours, &[9u8; 32], &binding), &proof, &key));
+        assert!(!NodeIdentity::verify(&transcript(&ours, &theirs, &[9u8; 32]), &proof, &key));
+        // Nor can it be reflected back as the other side's proof
+        assert!(!NodeIdentity::verify(&transcript(&theirs, &ours, &binding), &proof, &key));
+    }
+
+    #[tokio::test]
+    async fn test_only_a_completed_handshake_authenticates() {
+        let (alice, bob, mallory) = (NodeIdentity::generate(), NodeIdentity::generate(), NodeIdentity::generate());
+        let (alice_key, bob_key) = (alice.public_key_bytes(), bob.public_key_bytes());
+        let timeout = Duration::from_secs(1);
+        let connect = || {
+            let (a, b) = tokio::io::duplex(64 * 1024);
+            (Connection::new(Framed::new(a, SentinelCodec::new())), Connection::new(Framed::new(b, SentinelCodec::new())))
+        };
+
+        let (a, b) = connect();
+        let (a, b) = tokio::join!(
+            perform(a, hello(&alice), &bob_key, b"binding", timeout),
+            perform(b, hello(&bob), &alice_key, b"binding", timeout),
+        );
+        assert_eq!(a.unwrap().user_id(), bob.node_id());
+        assert_eq!(b.unwrap().user_id(), alice.node_id());
+
+        // A peer whose messages are signed by another key than the one pinned by TLS
+        let (a, b) = connect();
+        let (a, _) = tokio::join!(
+            perform(a, hello(&alice), &bob_key, b"binding", timeout),
+            perform(b, hello(&mallory), &alice_key, b"binding", timeout),
+        );
+        assert!(a.is_err());
+
+        // Proofs made for another TLS session
+        let (a, b) = connect();
+        let (a, b) = tokio::join!(
+            perform(a, hello(&alice), &bob_key, b"binding", timeout),
+            perform(b, hello(&bob), &alice_key, b"other", timeout),
+        );
+        assert!(a.is_err() && b.is_err());
+
+        // A peer that never answers
+        let (a, _b) = connect();
+        assert!(perform(a, hello(&alice), &bob_key, b"binding", timeout).await.is_err());
+    }
+}
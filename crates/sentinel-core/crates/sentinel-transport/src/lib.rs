@@ -5,14 +5,17 @@ pub mod acceptor;
 pub mod error;
 pub mod metrics;
 pub mod state;
+pub mod handshake;
 pub mod connector;
+pub mod verifier;
 
 pub use acceptor::SentinelAcceptor;
 pub use error::{TransportError, TransportResult};
 pub use tcp::RawTcpTransport;
 pub use tls::TlsTransport;
-pub use state::{Connection, Unauthenticated};
+pub use state::{Authenticated, Connection, Unauthenticated};
 pub use connector::SentinelConnector;
+pub use verifier::NodeCertVerifier;
 
 use tokio::io::{AsyncRead, AsyncWrite};
 use std::net::SocketAddr;
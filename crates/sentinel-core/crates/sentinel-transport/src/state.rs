@@ -1,20 +1,78 @@
 use std::marker::PhantomData;
 use async_trait::async_trait;
-use sentinel_protocol::Frame;
+use futures::{SinkExt, StreamExt};
+use sentinel_protocol::{SentinelCodec, SentinelMessage, SignedPreKey};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
 use anyhow::Result;
 
 #[async_trait]
-pub trait SentinelTransport: Send + Sync {
-    async fn send_frame(&mut self, frame: Frame) -> Result<()>;
-    async fn next_frame(&mut self) -> Result<Option<Frame>>;
+pub trait SentinelTransport: Send {
+    async fn send_message(&mut self, msg: SentinelMessage) -> Result<()>;
+    async fn next_message(&mut self) -> Result<Option<SentinelMessage>>;
+}
+
+#[async_trait]
+impl<S> SentinelTransport for Framed<S, SentinelCodec>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send_message(&mut self, msg: SentinelMessage) -> Result<()> {
+        Ok(self.send(msg).await?)
+    }
+
+    async fn next_message(&mut self) -> Result<Option<SentinelMessage>> {
+        Ok(self.next().await.transpose()?)
+    }
 }
 
 pub struct Unauthenticated;
-pub struct Authenticated { pub user_id: String }
+
+/// Proof that the peer completed the identity handshake. Only `handshake::perform`
+/// creates one, so a connection cannot skip it:
+///
+/// ```compile_fail
+/// use sentinel_transport::{state::SentinelTransport, Authenticated, Connection, Unauthenticated};
+///
+/// fn forge<T: SentinelTransport>(conn: Connection<T, Unauthenticated>) -> Connection<T, Authenticated> {
+///     conn.into_authenticated(todo!())
+/// }
+/// ```
+pub struct Authenticated {
+    pub(crate) user_id: String,
+    pub(crate) node_name: String,
+    pub(crate) public_key: Vec<u8>,
+    /// Port the peer accepts connections on, as announced in its handshake
+    pub(crate) listen_port: u16,
+    /// Signed prekey for starting end-to-end sessions with the peer
+    pub(crate) prekey: SignedPreKey,
+}
+
+impl Authenticated {
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    pub fn prekey(&self) -> &SignedPreKey {
+        &self.prekey
+    }
+}
 
 pub struct Connection<T: SentinelTransport, S> {
-    pub transport: T,
-    pub state_data: S,
+    transport: T,
+    state_data: S,
     _state: PhantomData<S>,
 }
 
@@ -27,33 +85,42 @@ impl<T: SentinelTransport> Connection<T, Unauthenticated> {
         }
     }
 
-    pub async fn send_frame(&mut self, frame: Frame) -> Result<()> {
-        self.transport.send_frame(frame).await
+    pub async fn send_message(&mut self, msg: SentinelMessage) -> Result<()> {
+        self.transport.send_message(msg).await
     }
 
-    pub async fn next_frame(&mut self) -> Result<Option<Frame>> {
-        self.transport.next_frame().await
+    pub async fn next_message(&mut self) -> Result<Option<SentinelMessage>> {
+        self.transport.next_message().await
     }
 
-    pub fn into_authenticated(self, user_id: String) -> Connection<T, Authenticated> {
+    pub(crate) fn into_authenticated(self, auth: Authenticated) -> Connection<T, Authenticated> {
         Connection {
             transport: self.transport,
-            state_data: Authenticated { user_id },
+            state_data: auth,
             _state: PhantomData,
         }
     }
 }
 
 impl<T: SentinelTransport> Connection<T, Authenticated> {
+    pub fn peer(&self) -> &Authenticated {
+        &self.state_data
+    }
+
     pub fn user_id(&self) -> &str {
-        &self.state_data.user_id
+        self.state_data.user_id()
     }
 
-    pub async fn send_frame(&mut self, frame: Frame) -> Result<()> {
-        self.transport.send_frame(frame).await
+    pub async fn send_message(&mut self, msg: SentinelMessage) -> Result<()> {
+        self.transport.send_message(msg).await
     }
 
-    pub async fn next_frame(&mut self) -> Result<Option<Frame>> {
-        self.transport.next_frame().await
+    pub async fn next_message(&mut self) -> Result<Option<SentinelMessage>> {
+        self.transport.next_message().await
     }
-}
\ No newline at end of file
+
+    /// Releases the transport so it can be split into independent reader/writer tasks
+    pub fn into_parts(self) -> (T, Authenticated) {
+        (self.transport, self.state_data)
+    }
+}
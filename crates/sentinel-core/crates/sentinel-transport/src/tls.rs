@@ -1,4 +1,5 @@
 use crate::SentinelTransport;
+use crate::verifier::peer_public_key;
 use async_trait::async_trait;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -7,6 +8,8 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_rustls::TlsStream;
 use tokio::net::TcpStream;
 
+const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-sentinel-channel-binding";
+
 pub struct TlsTransport<S = TcpStream> {
     pub(crate) inner: TlsStream<S>,
 }
@@ -15,6 +18,23 @@ impl<S> TlsTransport<S> {
     pub fn new(inner: TlsStream<S>) -> Self {
         Self { inner }
     }
+
+    /// Ed25519 node key the remote side proved during the TLS handshake
+    pub fn peer_public_key(&self) -> Option<Vec<u8>> {
+        let (_, session) = self.inner.get_ref();
+        session.peer_certificates()?.first().and_then(peer_public_key)
+    }
+
+    /// RFC 5705 keying material unique to this TLS session, used to bind application
+    /// handshakes to the channel so they cannot be replayed over another connection
+    pub fn channel_binding(&self) -> Option<[u8; 32]> {
+        let out = [0u8; 32];
+        match &self.inner {
+            TlsStream::Client(s) => s.get_ref().1.export_keying_material(out, CHANNEL_BINDING_LABEL, None),
+            TlsStream::Server(s) => s.get_ref().1.export_keying_material(out, CHANNEL_BINDING_LABEL, None),
+        }
+        .ok()
+    }
 }
 
 #[async_trait]
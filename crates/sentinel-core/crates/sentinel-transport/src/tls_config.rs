@@ -1,9 +1,20 @@
 use std::path::Path;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
 use rustls_pemfile::{certs, private_key};
-use std::fs::File;
+use sentinel_crypto::NodeIdentity;
+use crate::verifier::peer_public_key;
+use std::fs::{self, File};
 use std::io::BufReader;
 
+pub const NODE_CERT_FILE: &str = "node.crt";
+pub const NODE_KEY_FILE: &str = "node.key";
+
+/// Hostname presented in SNI and the certificate SAN. Identity is carried by the key, not the name.
+pub const SENTINEL_SERVER_NAME: &str = "sentinel-node.local";
+
 pub fn load_certs(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
     let mut reader = BufReader::new(File::open(path)?);
     certs(&mut reader).collect()
@@ -13,4 +24,96 @@ pub fn load_private_key(path: &Path) -> std::io::Result<PrivateKeyDer<'static>>
     let mut reader = BufReader::new(File::open(path)?);
     private_key(&mut reader)?
         .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No private key found"))
-}
\ No newline at end of file
+}
+
+/// Mints a self-signed certificate whose key is the node's Ed25519 identity key
+pub fn self_signed_cert(
+    identity: &NodeIdentity,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let der = identity.to_pkcs8_der()?;
+    let key_der = PrivatePkcs8KeyDer::from(der.as_slice());
+    let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(&key_der, &rcgen::PKCS_ED25519)?;
+
+    let mut params = rcgen::CertificateParams::new(vec![SENTINEL_SERVER_NAME.to_string()])?;
+    params.distinguished_name.push(rcgen::DnType::CommonName, identity.node_id());
+    // A fresh serial per mint so a rotated certificate is distinguishable from the old one
+    let serial = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+    params.serial_number = Some(rcgen::SerialNumber::from(serial));
+    let cert = params.self_signed(&key_pair)?;
+
+    Ok((vec![cert.der().clone()], PrivateKeyDer::Pkcs8(key_der.clone_key())))
+}
+
+/// Loads `node.crt`/`node.key` from `dir`. On first run, or if the stored certificate
+/// no longer matches the identity key, a new one is minted and written to disk.
+pub fn load_or_generate_node_cert(
+    identity: &NodeIdentity,
+    dir: &Path,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let cert_path = dir.join(NODE_CERT_FILE);
+    let key_path = dir.join(NODE_KEY_FILE);
+
+    if cert_path.exists() && key_path.exists() {
+        let certs = load_certs(&cert_path)?;
+        let key = load_private_key(&key_path)?;
+        let bound = certs.first().and_then(peer_public_key) == Some(identity.public_key_bytes());
+        if bound {
+            return Ok((certs, key));
+        }
+    }
+
+    rotate_node_cert(identity, dir)
+}
+
+/// Mints a new certificate for the identity and overwrites the stored pair in `dir`
+pub fn rotate_node_cert(
+    identity: &NodeIdentity,
+    dir: &Path,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let (certs, key) = self_signed_cert(identity)?;
+
+    let cert_pem: String = certs.iter().map(|c| pem_encode("CERTIFICATE", c)).collect();
+    fs::write(dir.join(NODE_CERT_FILE), cert_pem)?;
+
+    let key_path = dir.join(NODE_KEY_FILE);
+    fs::write(&key_path, pem_encode("PRIVATE KEY", key.secret_der()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok((certs, key))
+}
+
+fn pem_encode(tag: &str, der: &[u8]) -> String {
+    pem::encode(&pem::Pem::new(tag, der.to_vec()))
+}
+
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_cert_persisted_and_rebound() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = NodeIdentity::generate();
+
+        let (first, _) = load_or_generate_node_cert(&identity, dir.path()).unwrap();
+        let (reloaded, _) = load_or_generate_node_cert(&identity, dir.path()).unwrap();
+        assert_eq!(first, reloaded);
+
+        let (rotated, _) = rotate_node_cert(&identity, dir.path()).unwrap();
+        assert_ne!(first, rotated);
+        assert_eq!(peer_public_key(&rotated[0]), Some(identity.public_key_bytes()));
+
+        // A certificate left behind by another identity is replaced, not served
+        let other = NodeIdentity::generate();
+        let (replaced, _) = load_or_generate_node_cert(&other, dir.path()).unwrap();
+        assert_eq!(peer_public_key(&replaced[0]), Some(other.public_key_bytes()));
+    }
+}
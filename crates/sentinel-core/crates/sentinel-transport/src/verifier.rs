@@ -0,0 +1,168 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, Error, SignatureScheme};
+use sentinel_crypto::NodeIdentity;
+
+/// Extracts the Ed25519 node key from a peer certificate.
+/// Returns None for anything that is not an Ed25519 certificate.
+pub fn peer_public_key(cert: &CertificateDer<'_>) -> Option<Vec<u8>> {
+    let parsed = webpki::EndEntityCert::try_from(cert).ok()?;
+    NodeIdentity::public_key_from_spki(&parsed.subject_public_key_info())
+}
+
+/// Binds the TLS certificate to the peer's Ed25519 `NodeIdentity`.
+///
+/// Sentinel certificates are self-signed, so there is no CA chain to walk. Instead the
+/// certificate must carry an Ed25519 key, and the TLS CertificateVerify signature proves
+/// the peer holds the matching private key. When `expected_key` is set, the key must
+/// also match it exactly (used when dialing a known node ID).
+#[derive(Debug)]
+pub struct NodeCertVerifier {
+    expected_key: Option<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl NodeCertVerifier {
+    pub fn new(expected_key: Option<Vec<u8>>) -> Self {
+        Self {
+            expected_key,
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+
+    fn check_identity(&self, end_entity: &CertificateDer<'_>) -> Result<(), Error> {
+        let key = peer_public_key(end_entity)
+            .ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        match &self.expected_key {
+            Some(expected) if *expected != key => Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for NodeCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        self.check_identity(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ClientCertVerifier for NodeCertVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        self.check_identity(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls_config::{self_signed_cert, SENTINEL_SERVER_NAME};
+    use crate::{SentinelAcceptor, SentinelConnector};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn dial(expected_key: Option<Vec<u8>>) -> (anyhow::Result<Vec<u8>>, NodeIdentity) {
+        let server_id = NodeIdentity::generate();
+        let client_id = NodeIdentity::generate();
+        let (certs, key) = self_signed_cert(&server_id).unwrap();
+        let acceptor = SentinelAcceptor::new(certs, key, Duration::from_secs(5)).unwrap();
+        let (certs, key) = self_signed_cert(&client_id).unwrap();
+        let connector = SentinelConnector::new(certs, key);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.ok().and_then(|tls| tls.peer_public_key())
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let expected = expected_key.unwrap_or_else(|| server_id.public_key_bytes());
+        let client = connector.connect(SENTINEL_SERVER_NAME, stream, Some(expected)).await;
+        let result = client.map(|tls| tls.peer_public_key().unwrap());
+
+        if result.is_ok() {
+            assert_eq!(server.await.unwrap(), Some(client_id.public_key_bytes()));
+        }
+        (result, server_id)
+    }
+
+    #[tokio::test]
+    async fn test_pinned_key_accepted() {
+        let (result, server_id) = dial(None).await;
+        assert_eq!(result.unwrap(), server_id.public_key_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_key_rejected() {
+        let impostor = NodeIdentity::generate();
+        let (result, _) = dial(Some(impostor.public_key_bytes())).await;
+        assert!(result.is_err());
+    }
+}
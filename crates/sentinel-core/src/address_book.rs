@@ -0,0 +1,367 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sentinel_crypto::session::verify_prekey;
+use sentinel_protocol::{PeerInfo, SignedPreKey};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::engine::unix_now;
+
+/// Connections the auto-dialer aims to keep open
+pub const DEFAULT_TARGET_PEERS: usize = 8;
+/// Entries that failed this many dials in a row are forgotten
+const MAX_FAILURES: u32 = 8;
+/// Gossip about a node older than this is not worth dialing
+const MAX_AGE_SECS: u64 = 7 * 24 * 3600;
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+/// Addresses remembered per node
+const MAX_ADDRESSES: usize = 8;
+/// Nodes remembered at once; gossip beyond this evicts the worst unverified entry
+pub const MAX_ENTRIES: usize = 1024;
+/// Distinct reporters remembered per node, which is also where they stop adding to the score
+const MAX_REPORTERS: usize = 5;
+
+/// Everything we know about a node we are not necessarily connected to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub node_id: String,
+    /// Known listen addresses, most preferred first
+    pub addresses: Vec<SocketAddr>,
+    pub node_name: String,
+    /// Ed25519 key proven in a handshake; empty until we have connected ourselves
+    pub public_key: Vec<u8>,
+    /// Newest prekey signed by the node, for starting sessions with it
+    pub prekey: Option<SignedPreKey>,
+    /// Unix time (seconds) we first learned of the node
+    pub first_seen: u64,
+    /// Unix time (seconds) the node was last heard from, by us or a gossiping peer
+    pub last_seen: u64,
+    /// Distinct peers that gossiped the node, a weak signal that it is real and reachable
+    pub reporters: Vec<String>,
+    /// Whether we ever completed a handshake with it
+    pub verified: bool,
+    /// Failed dials since the last successful one
+    pub failures: u32,
+    #[serde(skip)]
+    last_attempt: Option<Instant>,
+}
+
+impl AddressEntry {
+    fn new(info: PeerInfo) -> Self {
+        let mut entry = Self {
+            node_id: info.node_id,
+            addresses: vec![info.address],
+            node_name: info.node_name,
+            public_key: Vec::new(),
+            prekey: None,
+            first_seen: info.last_seen,
+            last_seen: info.last_seen,
+            reporters: Vec::new(),
+            verified: false,
+            failures: 0,
+            last_attempt: None,
+        };
+        entry.update_prekey(info.prekey);
+        entry
+    }
+
+    /// Higher is better: verified and widely reported nodes first, recent failures and
+    /// stale sightings last
+    pub fn score(&self, now: u64) -> i64 {
+        let hours_stale = now.saturating_sub(self.last_seen) / 3600;
+        let mut score = self.reporters.len() as i64 - 5 * i64::from(self.failures) - hours_stale as i64;
+        if self.verified {
+            score += 10;
+        }
+        score
+    }
+
+    /// Exponential backoff after failed dials, so dead addresses are retried less and less often
+    fn backing_off(&self) -> bool {
+        let backoff = BASE_BACKOFF * 2u32.pow(self.failures.min(6));
+        self.last_attempt.is_some_and(|at| at.elapsed() < backoff)
+    }
+
+    /// Keeps `prekey` if the node signed it and it is newer than the one we have
+    fn update_prekey(&mut self, prekey: Option<SignedPreKey>) {
+        let Some(prekey) = prekey else { return };
+        let newer = self.prekey.as_ref().is_none_or(|known| prekey.created > known.created);
+        let signed = hex::decode(&self.node_id)
+            .is_ok_and(|owner| verify_prekey(&owner, &prekey.key, prekey.created, &prekey.signature));
+        if newer && signed {
+            self.prekey = Some(prekey);
+        }
+    }
+
+    fn prefer_address(&mut self, address: SocketAddr) {
+        self.addresses.retain(|a| *a != address);
+        self.addresses.insert(0, address);
+        self.addresses.truncate(MAX_ADDRESSES);
+    }
+
+    fn add_address(&mut self, address: SocketAddr) {
+        if !self.addresses.contains(&address) && self.addresses.len() < MAX_ADDRESSES {
+            self.addresses.push(address);
+        }
+    }
+}
+
+/// Candidate peers learned from gossip and past connections, ranked for the auto-dialer.
+/// When opened on a sled DB, every change is written to its `peers` tree, so a restarted
+/// node can find its way back into the mesh without mDNS or the signaler.
+#[derive(Default)]
+pub struct AddressBook {
+    entries: DashMap<String, AddressEntry>,
+    tree: Option<sled::Tree>,
+}
+
+impl AddressBook {
+    /// In-memory only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the `peers` tree of `db` and keeps it in sync. Unreadable entries are skipped,
+    /// and a tree holding more than `MAX_ENTRIES` is trimmed like a live book.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree("peers")?;
+        let entries = DashMap::new();
+        for item in tree.iter() {
+            let (_, value) = item?;
+            if let Ok(entry) = bincode::deserialize::<AddressEntry>(&value) {
+                entries.insert(entry.node_id.clone(), entry);
+            }
+        }
+        let book = Self { entries, tree: Some(tree) };
+        book.evict_over_limit(unix_now());
+        Ok(book)
+    }
+
+    fn save(&self, entry: &AddressEntry) {
+        if let (Some(tree), Ok(bytes)) = (&self.tree, bincode::serialize(entry)) {
+            let _ = tree.insert(entry.node_id.as_bytes(), bytes);
+        }
+    }
+
+    fn forget(&self, node_id: &str) {
+        self.entries.remove(node_id);
+        if let Some(tree) = &self.tree {
+            let _ = tree.remove(node_id.as_bytes());
+        }
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<AddressEntry> {
+        self.entries.get(node_id).map(|e| e.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Merges a contact gossiped by `reporter`. A newer sighting also updates the address,
+    /// unless we already reached the node ourselves at a different one. Sightings from the
+    /// future count as `now`, and a full book evicts its worst unverified entry, which may
+    /// be the new one.
+    pub fn merge(&self, mut info: PeerInfo, reporter: &str, now: u64) {
+        info.last_seen = info.last_seen.min(now);
+        let node_id = info.node_id.clone();
+        let mut entry = self.entries.entry(info.node_id.clone()).or_insert_with(|| AddressEntry::new(info.clone()));
+        if !entry.reporters.iter().any(|r| r == reporter) && entry.reporters.len() < MAX_REPORTERS {
+            entry.reporters.push(reporter.to_string());
+        }
+        if info.last_seen > entry.last_seen && !entry.verified {
+            entry.prefer_address(info.address);
+            entry.node_name = info.node_name;
+        } else {
+            entry.add_address(info.address);
+        }
+        entry.last_seen = entry.last_seen.max(info.last_seen);
+        entry.update_prekey(info.prekey);
+        drop(entry);
+        self.evict_over_limit(now);
+        // Written only once it survived the limit, so gossip cannot grow the tree either
+        if let Some(entry) = self.entries.get(&node_id) {
+            self.save(&entry);
+        }
+    }
+
+    /// Forgets the worst-scored unverified entries, most recently learned first among
+    /// equals, until the book is back within `MAX_ENTRIES`
+    fn evict_over_limit(&self, now: u64) {
+        while self.entries.len() > MAX_ENTRIES {
+            let worst = self.entries.iter()
+                .filter(|e| !e.verified)
+                .min_by_key(|e| (e.score(now), std::cmp::Reverse(e.first_seen)))
+                .map(|e| e.key().clone());
+            match worst {
+                Some(node_id) => self.forget(&node_id),
+                None => return,
+            }
+        }
+    }
+
+    /// Records a completed handshake at `address`, the node's dialable listen address
+    pub fn record_connected(&self, node_id: &str, public_key: &[u8], prekey: &SignedPreKey, address: SocketAddr, node_name: &str, now: u64) {
+        let mut entry = self.entries.entry(node_id.to_string()).or_insert_with(|| AddressEntry::new(PeerInfo {
+            node_id: node_id.to_string(),
+            address,
+            node_name: node_name.to_string(),
+            last_seen: now,
+            prekey: None,
+        }));
+        entry.prefer_address(address);
+        entry.node_name = node_name.to_string();
+        entry.public_key = public_key.to_vec();
+        entry.prekey = Some(prekey.clone());
+        entry.last_seen = now;
+        entry.verified = true;
+        entry.failures = 0;
+        self.save(&entry);
+    }
+
+    /// Records a failed dial; the entry is dropped after too many in a row
+    pub fn record_failure(&self, node_id: &str) {
+        let forget = match self.entries.get_mut(node_id) {
+            Some(mut entry) => {
+                entry.failures += 1;
+                self.save(&entry);
+                entry.failures >= MAX_FAILURES
+            }
+            None => false,
+        };
+        if forget {
+            self.forget(node_id);
+        }
+    }
+
+    /// Picks up to `count` dial candidates, best score first, skipping excluded nodes,
+    /// stale entries and those backing off. Picked entries are marked as attempted.
+    pub fn candidates(&self, count: usize, now: u64, exclude: impl Fn(&str) -> bool) -> Vec<AddressEntry> {
+        let mut picks: Vec<AddressEntry> = self.entries.iter()
+            .filter(|e| !exclude(e.key()) && !e.backing_off())
+            .filter(|e| now.saturating_sub(e.last_seen) < MAX_AGE_SECS)
+            .map(|e| e.value().clone())
+            .collect();
+        picks.sort_by_key(|e| std::cmp::Reverse(e.score(now)));
+        picks.truncate(count);
+
+        for pick in &picks {
+            if let Some(mut entry) = self.entries.get_mut(&pick.node_id) {
+                entry.last_attempt = Some(Instant::now());
+            }
+        }
+        picks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(id: &str, port: u16, last_seen: u64) -> PeerInfo {
+        PeerInfo {
+            node_id: id.to_string(),
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            node_name: String::new(),
+            last_seen,
+            prekey: None,
+        }
+    }
+
+    fn prekey() -> SignedPreKey {
+        SignedPreKey { key: vec![9; 32], created: 1, signature: Vec::new() }
+    }
+
+    #[test]
+    fn test_candidates_ranked_and_backed_off() {
+        let now = 1_000_000;
+        let book = AddressBook::new();
+        book.merge(info("gossiped", 1, now), "r", now);
+        book.merge(info("failing", 2, now), "r", now);
+        book.record_failure("failing");
+        book.record_connected("verified", &[], &prekey(), SocketAddr::from(([127, 0, 0, 1], 3)), "v", now);
+        book.merge(info("stale", 4, now - MAX_AGE_SECS), "r", now);
+
+        let picks: Vec<String> = book.candidates(10, now, |_| false).into_iter().map(|e| e.node_id).collect();
+        assert_eq!(picks, ["verified", "gossiped", "failing"]);
+        // Just attempted, so nothing is offered again until the backoff expires
+        assert!(book.candidates(10, now, |_| false).is_empty());
+    }
+
+    #[test]
+    fn test_gossip_does_not_override_verified_address() {
+        let book = AddressBook::new();
+        let ours = SocketAddr::from(([10, 0, 0, 1], 8443));
+        book.record_connected("node", &[7; 32], &prekey(), ours, "n", 10);
+        book.merge(info("node", 9999, 20), "r", 30);
+        let entry = book.get("node").unwrap();
+        assert_eq!((entry.addresses[0], entry.last_seen, entry.reporters.len()), (ours, 20, 1));
+        assert_eq!(entry.addresses.len(), 2);
+    }
+
+    #[test]
+    fn test_entries_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let addr = SocketAddr::from(([10, 0, 0, 2], 8443));
+        {
+            let book = AddressBook::open(&db).unwrap();
+            book.record_connected("node", &[7; 32], &prekey(), addr, "n", 10);
+            book.record_failure("node");
+        }
+        let entry = AddressBook::open(&db).unwrap().get("node").unwrap();
+        assert_eq!((entry.addresses, entry.public_key, entry.first_seen, entry.failures), (vec![addr], vec![7; 32], 10, 1));
+        assert!(entry.verified);
+    }
+
+    #[test]
+    fn test_gossip_cannot_inflate_or_flood_the_book() {
+        let now = 1_000_000;
+        let book = AddressBook::new();
+        book.record_connected("verified", &[], &prekey(), SocketAddr::from(([127, 0, 0, 1], 1)), "v", now);
+
+        // The same neighbour repeating itself counts once; a claim from the future counts as now
+        for _ in 0..10 {
+            book.merge(info("echoed", 2, now + 3600), "neighbour", now);
+        }
+        book.merge(info("echoed", 2, now), "other", now);
+        let entry = book.get("echoed").unwrap();
+        assert_eq!((entry.reporters.len(), entry.last_seen), (2, now));
+
+        for i in 0..MAX_ENTRIES + 10 {
+            book.merge(info(&format!("made-up-{}", i), 3, now - 2000 + i as u64), "flooder", now);
+        }
+        assert_eq!(book.len(), MAX_ENTRIES);
+        assert!(book.get("verified").is_some() && book.get("echoed").is_some());
+        // Among equally scored entries, the newest are the ones dropped
+        assert!(book.get("made-up-0").is_some());
+        assert!(book.get(&format!("made-up-{}", MAX_ENTRIES + 9)).is_none());
+    }
+
+    #[test]
+    fn test_capped_book_reloads_capped() {
+        let (dir, now) = (tempfile::tempdir().unwrap(), unix_now());
+        let db = sled::open(dir.path()).unwrap();
+        {
+            let book = AddressBook::open(&db).unwrap();
+            for i in 0..MAX_ENTRIES + 50 {
+                book.merge(info(&format!("made-up-{}", i), 1, now), "flooder", now);
+            }
+        }
+        let tree = db.open_tree("peers").unwrap();
+        assert_eq!(tree.len(), MAX_ENTRIES);
+
+        // A tree that outgrew the limit, e.g. written before it existed, is trimmed on load
+        for i in 0..10 {
+            let entry = AddressEntry::new(info(&format!("old-{}", i), 1, now));
+            tree.insert(entry.node_id.as_bytes(), bincode::serialize(&entry).unwrap()).unwrap();
+        }
+        assert_eq!(AddressBook::open(&db).unwrap().len(), MAX_ENTRIES);
+        assert_eq!(tree.len(), MAX_ENTRIES);
+    }
+}
@@ -0,0 +1,242 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::address_book::DEFAULT_TARGET_PEERS;
+use crate::network::stun::DEFAULT_STUN_SERVERS;
+use crate::peers::{QueuePolicy, DEFAULT_QUEUE_CAPACITY};
+use sentinel_protocol::RelayLimits;
+
+/// Port nodes listen on unless configured otherwise
+pub const DEFAULT_PORT: u16 = 8443;
+/// Shortest interval the builder accepts; `tokio::time::interval` panics on zero
+pub const MIN_INTERVAL: Duration = Duration::from_millis(1);
+/// Shortest handshake timeout the builder accepts; below it no TLS handshake can finish
+pub const MIN_HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Everything an embedder can tune about a node. Build one with `NodeConfig::builder`;
+/// every setting not given keeps the default the engine has always used.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Holds the identity key, the TLS certificate and the sled database
+    pub data_dir: PathBuf,
+    /// Addresses to accept connections on. The default listens on both IPv4 and IPv6;
+    /// a lone IPv6 wildcard accepts IPv4 as well. The first port is announced to peers.
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Name announced in the handshake
+    pub node_name: String,
+    /// Limit for the TLS handshake and for the Sentinel handshake that follows it
+    pub handshake_timeout: Duration,
+    /// Interval between pings to each peer
+    pub heartbeat_interval: Duration,
+    /// Consecutive unanswered pings before a peer is evicted
+    pub max_missed_pongs: u32,
+    pub gossip_interval: Duration,
+    pub autodial_interval: Duration,
+    pub dht_refresh_interval: Duration,
+    /// Message IDs remembered to drop duplicates arriving over several paths
+    pub dedup_capacity: usize,
+    /// Connection count the auto-dialer works towards; 0 disables auto-dialing
+    pub target_peers: usize,
+    /// Capacity of each peer's send queue
+    pub queue_capacity: usize,
+    /// What a full send queue does with further messages
+    pub queue_policy: QueuePolicy,
+    /// Advertise and browse for nodes on the local network
+    pub mdns: bool,
+    /// Share connected peers with neighbours
+    pub gossip: bool,
+    /// Servers asked for the public address and NAT type; empty skips the check
+    pub stun_servers: Vec<String>,
+    /// Splice connections for peers that cannot reach each other, within these limits.
+    /// None (the default) declines relay requests.
+    pub relay: Option<RelayLimits>,
+    /// Rendezvous server; None runs the node without one
+    pub signaler_addr: Option<String>,
+    /// Node ID (hex Ed25519 key) the signaler must prove in TLS. None trusts the key the
+    /// signaler shows on the first connection and pins it in `data_dir` for later ones.
+    pub signaler_key: Option<String>,
+    /// PEM certificate to serve instead of the one minted in `data_dir`. It must carry
+    /// the node's identity key, since that is what peers pin.
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+impl NodeConfig {
+    pub fn builder(data_dir: impl Into<PathBuf>) -> NodeConfigBuilder {
+        NodeConfigBuilder {
+            config: NodeConfig {
+                data_dir: data_dir.into(),
+                listen_addrs: vec![
+                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, DEFAULT_PORT)),
+                ],
+                node_name: "Sentinel-Node".to_string(),
+                handshake_timeout: Duration::from_secs(10),
+                heartbeat_interval: Duration::from_secs(20),
+                max_missed_pongs: 3,
+                gossip_interval: Duration::from_secs(30),
+                autodial_interval: Duration::from_secs(15),
+                dht_refresh_interval: Duration::from_secs(60),
+                dedup_capacity: 1000,
+                target_peers: DEFAULT_TARGET_PEERS,
+                queue_capacity: DEFAULT_QUEUE_CAPACITY,
+                queue_policy: QueuePolicy::default(),
+                mdns: true,
+                gossip: true,
+                stun_servers: DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect(),
+                relay: None,
+                signaler_addr: None,
+                signaler_key: None,
+                cert_path: None,
+                key_path: None,
+            },
+        }
+    }
+}
+
+pub struct NodeConfigBuilder {
+    config: NodeConfig,
+}
+
+impl NodeConfigBuilder {
+    /// Listens on `addr` only
+    pub fn listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.listen_addrs = vec![addr];
+        self
+    }
+
+    pub fn listen_addrs(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.config.listen_addrs = addrs.into_iter().collect();
+        self
+    }
+
+    /// Keeps the listen IPs and changes only the port
+    pub fn listen_port(mut self, port: u16) -> Self {
+        for addr in &mut self.config.listen_addrs {
+            addr.set_port(port);
+        }
+        self
+    }
+
+    pub fn node_name(mut self, name: impl Into<String>) -> Self {
+        self.config.node_name = name.into();
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = timeout.max(MIN_HANDSHAKE_TIMEOUT);
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.config.heartbeat_interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    pub fn max_missed_pongs(mut self, count: u32) -> Self {
+        self.config.max_missed_pongs = count.max(1);
+        self
+    }
+
+    pub fn gossip_interval(mut self, interval: Duration) -> Self {
+        self.config.gossip_interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    pub fn autodial_interval(mut self, interval: Duration) -> Self {
+        self.config.autodial_interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    pub fn dht_refresh_interval(mut self, interval: Duration) -> Self {
+        self.config.dht_refresh_interval = interval.max(MIN_INTERVAL);
+        self
+    }
+
+    pub fn dedup_capacity(mut self, capacity: usize) -> Self {
+        self.config.dedup_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn target_peers(mut self, count: usize) -> Self {
+        self.config.target_peers = count;
+        self
+    }
+
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.config.queue_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> Self {
+        self.config.queue_policy = policy;
+        self
+    }
+
+    pub fn mdns(mut self, enabled: bool) -> Self {
+        self.config.mdns = enabled;
+        self
+    }
+
+    pub fn gossip(mut self, enabled: bool) -> Self {
+        self.config.gossip = enabled;
+        self
+    }
+
+    pub fn stun_servers(mut self, servers: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.stun_servers = servers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn relay(mut self, limits: RelayLimits) -> Self {
+        self.config.relay = Some(limits);
+        self
+    }
+
+    pub fn signaler_addr(mut self, addr: impl Into<String>) -> Self {
+        self.config.signaler_addr = Some(addr.into());
+        self
+    }
+
+    pub fn signaler_key(mut self, node_id: impl Into<String>) -> Self {
+        self.config.signaler_key = Some(node_id.into());
+        self
+    }
+
+    pub fn tls_files(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.config.cert_path = Some(cert_path.into());
+        self.config.key_path = Some(key_path.into());
+        self
+    }
+
+    pub fn build(self) -> NodeConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_overrides_only_given_settings() {
+        let config = NodeConfig::builder("/tmp/node")
+            .listen_port(9000)
+            .node_name("edge")
+            .heartbeat_interval(Duration::from_secs(5))
+            .queue_capacity(0)
+            .gossip_interval(Duration::ZERO)
+            .handshake_timeout(Duration::ZERO)
+            .build();
+        let ports: Vec<u16> = config.listen_addrs.iter().map(SocketAddr::port).collect();
+        assert_eq!(ports, [9000, 9000]);
+        assert!(config.listen_addrs[1].is_ipv6());
+        assert_eq!((config.node_name.as_str(), config.heartbeat_interval), ("edge", Duration::from_secs(5)));
+        // Zero would make every send fail, so the builder keeps at least one slot
+        assert_eq!(config.queue_capacity, 1);
+        assert_eq!(config.gossip_interval, MIN_INTERVAL);
+        assert_eq!(config.handshake_timeout, MIN_HANDSHAKE_TIMEOUT);
+        assert_eq!((config.max_missed_pongs, config.dedup_capacity, config.mdns), (3, 1000, true));
+    }
+}
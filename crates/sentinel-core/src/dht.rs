@@ -0,0 +1,360 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::join_all;
+use sentinel_protocol::{MessageContent, PeerInfo, SentinelMessage};
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::engine::{unix_now, SentinelNode};
+use crate::DisconnectReason;
+
+/// Bucket size and lookup width
+pub const K: usize = 20;
+/// Queries in flight per lookup round
+pub const ALPHA: usize = 3;
+const ID_BITS: usize = 256;
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+type NodeKey = [u8; 32];
+
+/// Node IDs are hex Ed25519 keys; anything else has no place in the keyspace
+pub fn node_key(node_id: &str) -> Option<NodeKey> {
+    hex::decode(node_id).ok()?.try_into().ok()
+}
+
+/// Kademlia XOR metric. Compared lexicographically, so `[u8; 32]` ordering is distance ordering.
+pub fn distance(a: &NodeKey, b: &NodeKey) -> NodeKey {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// k-buckets indexed by the length of the prefix a contact shares with the local node
+pub struct RoutingTable {
+    local: NodeKey,
+    buckets: Mutex<Vec<VecDeque<PeerInfo>>>,
+}
+
+impl RoutingTable {
+    pub fn new(local_id: &str) -> Self {
+        Self {
+            local: node_key(local_id).unwrap_or([0; 32]),
+            buckets: Mutex::new(vec![VecDeque::new(); ID_BITS]),
+        }
+    }
+
+    fn bucket_index(&self, key: &NodeKey) -> Option<usize> {
+        let d = distance(&self.local, key);
+        let prefix: u32 = d.iter()
+            .scan(true, |zero, byte| {
+                let bits = if *zero { byte.leading_zeros() } else { 0 };
+                *zero = *zero && *byte == 0;
+                Some(bits)
+            })
+            .sum();
+        (prefix < ID_BITS as u32).then_some(prefix as usize)
+    }
+
+    /// Records a contact that was heard from directly. Known contacts move to the tail
+    /// (most recently seen); a full bucket keeps its long-lived contacts and ignores the newcomer.
+    pub fn update(&self, contact: PeerInfo) {
+        let Some(index) = node_key(&contact.node_id).and_then(|key| self.bucket_index(&key)) else {
+            return;
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[index];
+        if let Some(pos) = bucket.iter().position(|c| c.node_id == contact.node_id) {
+            bucket.remove(pos);
+        } else if bucket.len() >= K {
+            return;
+        }
+        bucket.push_back(contact);
+    }
+
+    /// Forgets a contact that could not be reached, making room for fresh ones
+    pub fn remove(&self, node_id: &str) {
+        let Some(index) = node_key(node_id).and_then(|key| self.bucket_index(&key)) else {
+            return;
+        };
+        self.buckets.lock().unwrap()[index].retain(|c| c.node_id != node_id);
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<PeerInfo> {
+        let index = node_key(node_id).and_then(|key| self.bucket_index(&key))?;
+        self.buckets.lock().unwrap()[index].iter().find(|c| c.node_id == node_id).cloned()
+    }
+
+    /// Up to `count` known contacts, nearest to `target` first
+    pub fn closest(&self, target: &NodeKey, count: usize) -> Vec<PeerInfo> {
+        let mut contacts: Vec<PeerInfo> = self.buckets.lock().unwrap().iter().flatten().cloned().collect();
+        sort_by_distance(&mut contacts, target);
+        contacts.truncate(count);
+        contacts
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn sort_by_distance(contacts: &mut [PeerInfo], target: &NodeKey) {
+    contacts.sort_by_cached_key(|c| node_key(&c.node_id).map(|key| distance(&key, target)).unwrap_or([0xff; 32]));
+}
+
+/// Finds the contact record of `target` through the mesh (iterative FIND_VALUE).
+/// Returns None once the lookup converges without any node knowing the target. A record
+/// found remotely goes to the address book, along with the prekey it carries.
+pub async fn lookup(node: &Arc<SentinelNode>, target: &str) -> Result<Option<PeerInfo>> {
+    if let Some(contact) = node.dht.get(target) {
+        return Ok(Some(contact));
+    }
+    let (found, _) = find_through_mesh(node, target, true).await?;
+    if let Some(contact) = &found {
+        node.address_book.merge(contact.clone(), &node.identity.node_id(), unix_now());
+    }
+    Ok(found)
+}
+
+/// Looks up our own ID every `dht_refresh_interval`, which fills the buckets near us and
+/// tells the nodes we query about our existence
+pub async fn start_refresh_service(node: Arc<SentinelNode>) {
+    let mut interval = tokio::time::interval(node.config.dht_refresh_interval);
+    loop {
+        if !node.tick(&mut interval).await {
+            return;
+        }
+        if !node.dht.is_empty() {
+            let _ = find_through_mesh(&node, &node.identity.node_id(), false).await;
+        }
+    }
+}
+
+/// Runs a lookup over live connections. Contacts it had to dial are disconnected again
+/// afterwards, except those that bring the node up to `target_peers`.
+async fn find_through_mesh(node: &Arc<SentinelNode>, target: &str, want_value: bool) -> Result<(Option<PeerInfo>, Vec<PeerInfo>)> {
+    let opened = Mutex::new(Vec::new());
+    let opened_ref = &opened;
+    let result = iterative_find(&node.dht, &node.identity.node_id(), target, want_value, move |contact, request| {
+        query(node, contact, request, opened_ref)
+    }).await;
+
+    for node_id in opened.into_inner().unwrap().into_iter().rev() {
+        if node.peers.len() <= node.config.target_peers {
+            break;
+        }
+        node.disconnect(&node_id, DisconnectReason::Local("DHT lookup finished".into()));
+    }
+    result
+}
+
+/// Iterative Kademlia lookup starting from `table`. `query` sends one request to a
+/// contact and returns its reply; contacts that fail it are dropped from the table.
+async fn iterative_find<F, Fut>(table: &RoutingTable, own_id: &str, target: &str, want_value: bool, query: F) -> Result<(Option<PeerInfo>, Vec<PeerInfo>)>
+where
+    F: Fn(PeerInfo, MessageContent) -> Fut,
+    Fut: Future<Output = Result<MessageContent>>,
+{
+    let target_key = node_key(target).context("Invalid node ID")?;
+    let mut shortlist = table.closest(&target_key, K);
+    let mut queried: HashSet<String> = HashSet::from([own_id.to_string()]);
+
+    loop {
+        let round: Vec<PeerInfo> = shortlist.iter()
+            .filter(|c| !queried.contains(&c.node_id))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+        if round.is_empty() {
+            return Ok((None, shortlist));
+        }
+
+        let request = if want_value {
+            MessageContent::FindValue { target: target.to_string() }
+        } else {
+            MessageContent::FindNode { target: target.to_string() }
+        };
+        let replies = join_all(round.iter().map(|contact| query(contact.clone(), request.clone()))).await;
+
+        for (contact, reply) in round.iter().zip(replies) {
+            queried.insert(contact.node_id.clone());
+            match reply {
+                Ok(MessageContent::Value { node: found, .. }) if found.node_id == target => {
+                    return Ok((Some(found), shortlist));
+                }
+                Ok(MessageContent::Nodes { nodes, .. }) => {
+                    if let Some(found) = nodes.iter().find(|c| want_value && c.node_id == target) {
+                        return Ok((Some(found.clone()), shortlist));
+                    }
+                    for candidate in nodes {
+                        let known = candidate.node_id == own_id
+                            || shortlist.iter().any(|c| c.node_id == candidate.node_id);
+                        if !known && node_key(&candidate.node_id).is_some() {
+                            shortlist.push(candidate);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    table.remove(&contact.node_id);
+                    shortlist.retain(|c| c.node_id != contact.node_id);
+                }
+            }
+        }
+        sort_by_distance(&mut shortlist, &target_key);
+        shortlist.truncate(K);
+    }
+}
+
+/// Sends one DHT request, dialing the contact first if we are not connected to it. A
+/// contact dialed here is added to `opened`.
+async fn query(node: &Arc<SentinelNode>, contact: PeerInfo, request: MessageContent, opened: &Mutex<Vec<String>>) -> Result<MessageContent> {
+    if !node.peers.contains(&contact.node_id) {
+        Arc::clone(node).dial_peer(contact.address.to_string(), Some(contact.node_id.clone()), node.event_sender()).await?;
+        opened.lock().unwrap().push(contact.node_id.clone());
+    }
+    let msg = node.sign_message(SentinelMessage::new(node.identity.node_id(), request));
+    let (tx, rx) = oneshot::channel();
+    node.dht_requests.insert(msg.id, tx);
+
+    let queue = node.peers.get(&contact.node_id).map(|peer| Arc::clone(&peer.queue));
+    let sent = queue.is_some_and(|queue| queue.push(msg.clone()));
+    let reply = match sent {
+        true => tokio::time::timeout(RPC_TIMEOUT, rx).await.ok().and_then(Result::ok),
+        false => None,
+    };
+    node.dht_requests.remove(&msg.id);
+    reply.ok_or_else(|| anyhow!("No DHT reply from {}", contact.node_id))
+}
+
+/// Answers FIND_NODE and FIND_VALUE from a connected peer and routes replies to their lookup
+pub(crate) fn handle_message(node: &SentinelNode, msg: &SentinelMessage, peer_id: &str) {
+    let reply = match &msg.content {
+        MessageContent::FindNode { target } | MessageContent::FindValue { target } => {
+            let wants_value = matches!(msg.content, MessageContent::FindValue { .. });
+            match node.dht.get(target).filter(|_| wants_value) {
+                Some(found) => MessageContent::Value { request_id: msg.id, node: found },
+                None => {
+                    let Some(key) = node_key(target) else { return };
+                    let nodes = node.dht.closest(&key, K + 1).into_iter()
+                        .filter(|c| c.node_id != peer_id)
+                        .take(K)
+                        .collect();
+                    MessageContent::Nodes { request_id: msg.id, nodes }
+                }
+            }
+        }
+        MessageContent::Nodes { request_id, .. } | MessageContent::Value { request_id, .. } => {
+            if let Some((_, waiter)) = node.dht_requests.remove(request_id) {
+                let _ = waiter.send(msg.content.clone());
+            }
+            return;
+        }
+        _ => return,
+    };
+    let _ = node.send_to(peer_id, reply);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn contact(key: NodeKey) -> PeerInfo {
+        PeerInfo {
+            node_id: hex::encode(key),
+            address: SocketAddr::from(([127, 0, 0, 1], 9000)),
+            node_name: String::new(),
+            last_seen: 0,
+            prekey: None,
+        }
+    }
+
+    #[test]
+    fn test_buckets_and_closest_follow_xor_distance() {
+        let table = RoutingTable::new(&hex::encode([0u8; 32]));
+        let mut far = [0u8; 32];
+        far[0] = 0x80;
+        let mut near = [0u8; 32];
+        near[31] = 0x01;
+        let mut middle = [0u8; 32];
+        middle[16] = 0x01;
+
+        assert_eq!(table.bucket_index(&far), Some(0));
+        assert_eq!(table.bucket_index(&near), Some(255));
+        assert_eq!(table.bucket_index(&[0u8; 32]), None);
+
+        for key in [far, near, middle] {
+            table.update(contact(key));
+        }
+        let order: Vec<String> = table.closest(&[0u8; 32], K).into_iter().map(|c| c.node_id).collect();
+        assert_eq!(order, [near, middle, far].map(hex::encode));
+    }
+
+    #[test]
+    fn test_full_bucket_keeps_old_contacts() {
+        let table = RoutingTable::new(&hex::encode([0u8; 32]));
+        // Every key with the top bit set lands in bucket 0
+        for i in 0..=K as u8 {
+            let mut key = [0u8; 32];
+            key[0] = 0x80;
+            key[31] = i;
+            table.update(contact(key));
+        }
+        assert_eq!(table.len(), K);
+        let mut newest = [0u8; 32];
+        newest[0] = 0x80;
+        newest[31] = K as u8;
+        assert!(table.get(&hex::encode(newest)).is_none());
+    }
+
+    fn key(first: u8) -> NodeKey {
+        let mut key = [0u8; 32];
+        key[0] = first;
+        key
+    }
+
+    fn nodes(contacts: &[NodeKey]) -> MessageContent {
+        MessageContent::Nodes { request_id: uuid::Uuid::new_v4(), nodes: contacts.iter().map(|k| contact(*k)).collect() }
+    }
+
+    #[tokio::test]
+    async fn test_iterative_find_follows_closer_contacts() {
+        let own = [0u8; 32];
+        let table = RoutingTable::new(&hex::encode(own));
+        let (far, silent, near, target) = (key(0x80), key(0x40), key(0x11), key(0x10));
+        table.update(contact(far));
+        table.update(contact(silent));
+
+        // `far` knows `near`, which knows the target; `silent` never answers
+        let replies = std::collections::HashMap::from([
+            (hex::encode(far), nodes(&[near, own])),
+            (hex::encode(near), nodes(&[far, target])),
+            (hex::encode(target), nodes(&[])),
+        ]);
+        let asked = Mutex::new(Vec::new());
+        let stub = |contact: PeerInfo, request: MessageContent| {
+            asked.lock().unwrap().push((contact.node_id.clone(), request));
+            let reply = replies.get(&contact.node_id).cloned();
+            async move { reply.context("No reply") }
+        };
+
+        let (found, _) = iterative_find(&table, &hex::encode(own), &hex::encode(target), true, &stub).await.unwrap();
+        assert_eq!(found.unwrap().node_id, hex::encode(target));
+        let asked: Vec<_> = asked.lock().unwrap().drain(..).collect();
+        assert!(asked.iter().all(|(_, request)| matches!(request, MessageContent::FindValue { .. })));
+        let mut ids: Vec<&str> = asked.iter().map(|(id, _)| id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, [hex::encode(near), hex::encode(silent), hex::encode(far)]);
+        assert!(table.get(&hex::encode(silent)).is_none());
+
+        // FIND_NODE converges on the closest contacts instead of stopping at the target
+        let (found, shortlist) = iterative_find(&table, &hex::encode(own), &hex::encode(target), false, &stub).await.unwrap();
+        assert!(found.is_none());
+        let order: Vec<String> = shortlist.into_iter().map(|c| c.node_id).collect();
+        assert_eq!(order, [target, near, far].map(hex::encode));
+    }
+}
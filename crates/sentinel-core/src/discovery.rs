@@ -1,44 +1,67 @@
 use anyhow::Result;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use crate::engine::SentinelNode;
 use mdns_sd::{ServiceInfo, ServiceEvent};
 
-pub async fn start_discovery(node: Arc<SentinelNode>, port: u16) -> Result<()> {
+/// Advertises the node over mDNS and dials the nodes it finds, if `mdns` is enabled
+pub async fn start_discovery(node: Arc<SentinelNode>) -> Result<()> {
+    if !node.config.mdns {
+        return Ok(());
+    }
+    let port = node.listen_port;
     let service_type = "_sentinel._tcp.local.";
-    let instance_name = format!("node-{}", &node.identity.node_id()[..8]);
+    let node_id = node.identity.node_id();
+    let instance_name = format!("node-{}", &node_id[..8]);
     
-    // register node so others can see usss
+    // register node so others can see usss, on every interface address, IPv4 and IPv6
     let my_info = ServiceInfo::new(
         service_type,
         &instance_name,
         &format!("{}.local.", instance_name),
-        "127.0.0.1", 
+        (),
         port,
-        None,
-    )?;
+        &[("id", node_id.as_str())][..],
+    )?
+    .enable_addr_auto();
     
+    let fullname = my_info.get_fullname().to_string();
     node.mdns.register(my_info)?;
+    *node.mdns_service.lock().unwrap() = Some(fullname);
 
     // browse abi look for other nodes
     let receiver = node.mdns.browse(service_type)?;
     
-    tokio::spawn(async move {
-        while let Ok(event) = receiver.recv_async().await {
+    let browser = Arc::clone(&node);
+    browser.spawn(async move {
+        loop {
+            let event = tokio::select! {
+                _ = node.stopped() => break,
+                event = receiver.recv_async() => match event {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+            };
             if let ServiceEvent::ServiceResolved(info) = event {
-                let addr = info.get_addresses().iter().next();
-                if let Some(ip) = addr {
-                    let full_addr = format!("{}:{}", ip, info.get_port());
-                    
-                    // only dial if we aren't already connected
-                    if !node.peers.contains_key(&full_addr) && ip.to_string() != "0.0.0.0" {
-                        let n = Arc::clone(&node);
-                        tokio::spawn(async move {
-                            if let Err(e) = n.dial_peer(full_addr).await {
-                                //  fail discovery dials to avoid spamming the console
-                                let _ = e; 
-                            }
-                        });
-                    }
+                let addrs: Vec<SocketAddr> = info.get_addresses().iter()
+                    .filter(|ip| !ip.is_unspecified())
+                    .map(|ip| SocketAddr::new(*ip, info.get_port()))
+                    .collect();
+                // the advertised node ID lets dial_peer pin the TLS key
+                let expected_id = info.get_property_val_str("id").map(str::to_string);
+
+                // only dial if we aren't already connected
+                let known = addrs.iter().any(|addr| node.peers.contains_addr(&addr.to_string()))
+                    || expected_id.as_deref().is_some_and(|id| node.peers.contains(id));
+                if !known && !addrs.is_empty() {
+                    let n = Arc::clone(&node);
+                    let events = n.event_sender();
+                    node.spawn_until_shutdown(async move {
+                        if let Err(e) = n.dial_any(&addrs, expected_id, events).await {
+                            //  fail discovery dials to avoid spamming the console
+                            let _ = e;
+                        }
+                    });
                 }
             }
         }
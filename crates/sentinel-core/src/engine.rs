@@ -1,154 +1,496 @@
 use anyhow::{Context, Result};
-use dashmap::DashMap;
-use futures::{future::{BoxFuture, FutureExt}, SinkExt, StreamExt};
+use futures::{future::{BoxFuture, FutureExt}, stream::FuturesUnordered, SinkExt, StreamExt};
 use lru::LruCache;
 use mdns_sd::ServiceDaemon;
-use sentinel_crypto::NodeIdentity;
+use dashmap::DashMap;
+use sentinel_crypto::session::{verify_prekey, Header};
+use sentinel_crypto::{NodeIdentity, PreKey, Session};
+use sentinel_protocol::frame::{MAGIC, MAGIC_LEN};
+use sentinel_protocol::relay::{Relay, RelayToken};
 use sentinel_protocol::{
-    messages::{MessageContent, PeerInfo, SentinelMessage},
+    messages::{MessageContent, PeerInfo, SentinelMessage, SignedPreKey, DEFAULT_TTL},
     SentinelCodec, SignalingMessage,
 };
-use sentinel_transport::{SentinelAcceptor, SentinelConnector};
+use sentinel_transport::tls_config::{load_certs, load_or_generate_node_cert, load_private_key, rotate_node_cert, SENTINEL_SERVER_NAME};
+use sentinel_transport::verifier::peer_public_key;
+use sentinel_transport::handshake::{self, Hello};
+use sentinel_transport::{Authenticated, Connection, SentinelAcceptor, SentinelConnector, TlsTransport};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream as TokioTcpStream;
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 
-use crate::network::socket::FighterSocket;
-use crate::SentinelEvent;
+use crate::address_book::AddressBook;
+use crate::config::NodeConfig;
+use crate::dht::{self, RoutingTable};
+use crate::network::{eyeballs, punch, socket::FighterSocket, stun::{self, NatType, StunReport}};
+use crate::peers::{ConnectionHandle, Direction, PeerState, PeerTable, Registration, SendQueue};
+use crate::{Delivery, DisconnectReason, SentinelEvent};
 
-pub struct PeerState {
-    pub tx: mpsc::UnboundedSender<SentinelMessage>,
-    pub node_id: String,
-    pub node_name: String,
-    pub public_key: Option<Vec<u8>>,
-    pub last_seen: std::time::Instant,
-}
+type PeerStream = Framed<TlsTransport<TokioTcpStream>, SentinelCodec>;
+
+/// How long a closing connection may take to flush its queue and close_notify
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the signed prekey is replaced. The previous secret is kept for one more period,
+/// so peers holding the old key can still start sessions, and then deleted.
+const PREKEY_ROTATION_SECS: u64 = 7 * 24 * 3600;
+/// Ratchet sessions kept per peer: both sides may start one at once, and messages sealed
+/// in either must still open
+const MAX_SESSIONS: usize = 3;
+/// Messages waiting for the signaler connection; further ones are dropped
+const SIGNALER_QUEUE_CAPACITY: usize = 64;
+/// How long a relay request waits for the relay's offer, and a relay for the other end's request
+const RELAY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct SentinelNode {
     pub identity: NodeIdentity,
+    pub config: NodeConfig,
+    pub data_dir: PathBuf,
     pub listen_port: u16,
     pub public_addr: RwLock<Option<SocketAddr>>,
+    /// Learned along with `public_addr` by `discover_and_set_public_ip`
+    pub nat_type: RwLock<Option<NatType>>,
     pub acceptor: SentinelAcceptor,
+    pub connector: SentinelConnector,
     pub db: sled::Db,
     pub mdns: ServiceDaemon,
-    pub peers: DashMap<String, PeerState>,
+    pub peers: PeerTable,
+    /// Kademlia contacts, fed by every verified connection
+    pub dht: RoutingTable,
+    /// Dial candidates learned from gossip and past connections
+    pub address_book: AddressBook,
     pub seen_messages: Mutex<LruCache<Uuid, ()>>,
-    pub signaler_tx: mpsc::UnboundedSender<SentinelMessage>,
+    /// Outbox of the signaler connection, bounded like the peer queues
+    pub signaler_tx: mpsc::Sender<SentinelMessage>,
+    next_conn_id: AtomicU64,
+    /// Ratchet sessions keyed by peer node ID, newest first, cached from the `sessions` tree
+    sessions: DashMap<String, Vec<Session>>,
+    /// DHT queries awaiting a reply, keyed by request message ID
+    pub(crate) dht_requests: DashMap<Uuid, oneshot::Sender<MessageContent>>,
+    /// The channel given to `run`, also used for connections opened by background services
+    events: OnceLock<mpsc::UnboundedSender<SentinelEvent>>,
+    /// Punches waiting for their peer, keyed by its address. A connection the listener
+    /// accepts from one of these addresses is handed to the punch instead.
+    punches: DashMap<SocketAddr, oneshot::Sender<TokioTcpStream>>,
+    /// Sessions spliced for peers that cannot reach each other, if `config.relay` opts in
+    relay: Option<Relay>,
+    /// Our outstanding relay requests, keyed by target: the relay asked and when the request
+    /// lapses. Each admits one matching `RelayOffer`; any other offer is ignored.
+    relay_requests: DashMap<String, (String, Instant)>,
+    /// Requests our relay holds until the target asks for the requester in turn, keyed by
+    /// `(requester, target)`
+    relay_asks: DashMap<(String, String), Instant>,
+    /// Mapped mDNS service name, unregistered on shutdown
+    pub(crate) mdns_service: std::sync::Mutex<Option<String>>,
+    /// Cancelled by `shutdown`; the listener and every service loop watch it
+    stopping: CancellationToken,
+    /// Connection tasks and services started through `spawn`, awaited by `shutdown`
+    tasks: TaskTracker,
 }
 
 impl SentinelNode {
-    /// Initializes a new SentinelNode instance with persistent storage and identity
-    pub async fn new(data_dir: PathBuf, listen_port: u16) -> Result<(Self, mpsc::UnboundedReceiver<SentinelMessage>)> {
+    /// Initializes a new SentinelNode instance with persistent storage and identity,
+    /// and default settings otherwise
+    pub async fn new(data_dir: PathBuf, listen_port: u16) -> Result<(Self, mpsc::Receiver<SentinelMessage>)> {
+        Self::with_config(NodeConfig::builder(data_dir).listen_port(listen_port).build()).await
+    }
+
+    pub async fn with_config(config: NodeConfig) -> Result<(Self, mpsc::Receiver<SentinelMessage>)> {
+        let data_dir = config.data_dir.clone();
         if !data_dir.exists() {
             std::fs::create_dir_all(&data_dir)?;
         }
         let identity = NodeIdentity::load_or_generate(data_dir.join("identity.key"))?;
         let db = sled::open(data_dir.join("storage.db"))?;
 
-        let cert_path = if data_dir.join("node.crt").exists() {
-            data_dir.join("node.crt")
-        } else {
-            PathBuf::from("certs/server.crt")
-        };
-        let key_path = if data_dir.join("node.key").exists() {
-            data_dir.join("node.key")
-        } else {
-            PathBuf::from("certs/server.key")
+        // TLS certificates carry the node key so peers can pin our identity
+        let (certs, key) = match (&config.cert_path, &config.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let certs = load_certs(cert_path).with_context(|| format!("Reading {}", cert_path.display()))?;
+                let key = load_private_key(key_path).with_context(|| format!("Reading {}", key_path.display()))?;
+                let bound = certs.first().and_then(peer_public_key) == Some(identity.public_key_bytes());
+                anyhow::ensure!(bound, "Certificate {} does not carry the node identity key", cert_path.display());
+                (certs, key)
+            }
+            (None, None) => load_or_generate_node_cert(&identity, &data_dir)?,
+            _ => anyhow::bail!("cert_path and key_path must be given together"),
         };
-
-        let acceptor = SentinelAcceptor::new(&cert_path, &key_path, Duration::from_secs(10))?;
+        if let Some(signaler_key) = &config.signaler_key {
+            let valid = hex::decode(signaler_key).is_ok_and(|key| key.len() == 32);
+            anyhow::ensure!(valid, "Signaler key {} is not a node ID", signaler_key);
+        }
+        let acceptor = SentinelAcceptor::new(certs.clone(), key.clone_key(), config.handshake_timeout)?;
+        let connector = SentinelConnector::new(certs, key);
         let mdns = ServiceDaemon::new().context("mDNS initialization failed")?;
-        let seen_messages = Mutex::new(LruCache::new(std::num::NonZeroUsize::new(1000).unwrap()));
+        let dht = RoutingTable::new(&identity.node_id());
+        let address_book = AddressBook::open(&db)?;
+        let listen_port = config.listen_addrs.first().context("No listen address configured")?.port();
+        let capacity = std::num::NonZeroUsize::new(config.dedup_capacity).context("dedup_capacity must not be zero")?;
+        let seen_messages = Mutex::new(LruCache::new(capacity));
 
-        let (signaler_tx, signaler_rx) = mpsc::unbounded_channel();
+        let (signaler_tx, signaler_rx) = mpsc::channel(SIGNALER_QUEUE_CAPACITY);
 
         Ok((
             Self {
                 identity,
+                data_dir,
                 listen_port,
                 public_addr: RwLock::new(None),
+                nat_type: RwLock::new(None),
                 acceptor,
+                connector,
                 db,
                 mdns,
-                peers: DashMap::new(),
+                peers: PeerTable::new(),
+                dht,
+                address_book,
                 seen_messages,
                 signaler_tx,
+                next_conn_id: AtomicU64::new(0),
+                sessions: DashMap::new(),
+                dht_requests: DashMap::new(),
+                events: OnceLock::new(),
+                punches: DashMap::new(),
+                relay: config.relay.map(Relay::new),
+                relay_requests: DashMap::new(),
+                relay_asks: DashMap::new(),
+                mdns_service: std::sync::Mutex::new(None),
+                stopping: CancellationToken::new(),
+                tasks: TaskTracker::new(),
+                config,
             },
             signaler_rx,
         ))
     }
 
-    /// The Main Engine Loop: Handles the TCP Listener and translates network bytes into SentinelEvents
+    /// The Main Engine Loop: Handles the TCP Listeners and translates network bytes into SentinelEvents.
+    /// Returns once `shutdown` is called. Of several listen addresses, those that cannot be
+    /// bound (e.g. IPv6 on a host without it) are reported and skipped.
     pub async fn run(self: Arc<Self>, event_tx: mpsc::UnboundedSender<SentinelEvent>) -> Result<()> {
-        let addr = format!("0.0.0.0:{}", self.listen_port);
-        let listener = tokio::net::TcpListener::bind(&addr).await?;
-        
-        let _ = event_tx.send(SentinelEvent::SystemLog(format!("Engine active on {}", addr)));
+        let _ = self.events.set(event_tx.clone());
+        let addrs = &self.config.listen_addrs;
+        // With an IPv4 listener of its own, the IPv6 one must leave IPv4 alone
+        let v6_only = addrs.iter().any(SocketAddr::is_ipv4);
+
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            let bound = FighterSocket::create_listener(*addr, v6_only)
+                .and_then(|listener| Ok(tokio::net::TcpListener::from_std(listener)?));
+            match bound {
+                Ok(listener) => {
+                    let _ = event_tx.send(SentinelEvent::Listening { addr: listener.local_addr()? });
+                    listeners.push(listener);
+                }
+                Err(e) if addrs.len() > 1 => {
+                    let _ = event_tx.send(SentinelEvent::SystemLog(format!("Cannot listen on {}: {}", addr, e)));
+                }
+                Err(e) => return Err(e.context(format!("Cannot listen on {}", addr))),
+            }
+        }
+        anyhow::ensure!(!listeners.is_empty(), "None of the listen addresses could be bound");
 
+        for listener in listeners {
+            self.tasks.spawn(Arc::clone(&self).accept_loop(listener, event_tx.clone()));
+        }
+        self.stopped().await;
+        Ok(())
+    }
+
+    async fn accept_loop(self: Arc<Self>, listener: tokio::net::TcpListener, event_tx: mpsc::UnboundedSender<SentinelEvent>) {
         loop {
-            let (stream, remote_addr) = listener.accept().await?;
+            let (stream, remote_addr) = tokio::select! {
+                _ = self.stopping.cancelled() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        let _ = event_tx.send(SentinelEvent::SystemLog(format!("Listener failed: {}", e)));
+                        return;
+                    }
+                },
+            };
+            // A dual-stack socket reports IPv4 clients as ::ffff:a.b.c.d
+            let remote_addr = SocketAddr::new(remote_addr.ip().to_canonical(), remote_addr.port());
+            let stream = match self.punches.remove(&remote_addr) {
+                Some((_, punch)) => match punch.send(stream) {
+                    Ok(()) => continue,
+                    Err(stream) => stream,
+                },
+                None => stream,
+            };
             let node = Arc::clone(&self);
             let tx = event_tx.clone();
-            let addr_str = remote_addr.to_string();
-
-            tokio::spawn(async move {
-                if let Ok(tls) = node.acceptor.accept(stream).await {
-                    let (mut sink, mut stream_in) = Framed::new(tls, SentinelCodec::new()).split();
-                    let (peer_tx, mut peer_rx) = mpsc::unbounded_channel();
-
-                    // 1. Handshake setup
-                    let hs = SentinelMessage::new(
-                        node.identity.node_id(),
-                        MessageContent::Handshake {
-                            public_key: node.identity.public_key_bytes(),
-                            node_name: "Sentinel-Core-Node".into(),
-                        },
-                    );
-                    node.sign_and_send(&peer_tx, hs);
-
-                    // 2. Register Peer internally
-                    node.peers.insert(addr_str.clone(), PeerState {
-                        tx: peer_tx,
-                        node_id: "pending".into(),
-                        node_name: "Inbound".into(),
-                        public_key: None,
-                        last_seen: std::time::Instant::now(),
-                    });
-
-                    // 3. Outbound Worker (Library Internal)
-                    tokio::spawn(async move {
-                        while let Some(msg) = peer_rx.recv().await {
-                            if sink.send(msg).await.is_err() { break; }
-                        }
-                    });
 
-                    // 4. Inbound Message Loop
-                    while let Some(Ok(msg)) = stream_in.next().await {
-                        // Emit high-level event for UI
-                        if let MessageContent::Chat(text) = &msg.content {
-                            if text != "PING" {
-                                let _ = tx.send(SentinelEvent::ChatMessage {
-                                    sender: msg.sender.clone(),
-                                    text: text.clone(),
-                                });
-                            }
-                        }
-                        
-                        // Process protocol logic
-                        let _ = node.clone().handle_incoming_message(msg, addr_str.clone()).await;
+            self.tasks.spawn(async move {
+                // Relay binds are plain Sentinel frames; everything else starts with TLS
+                if node.relay.is_some() && node.is_relay_bind(&stream).await {
+                    if let Err(e) = node.serve_relay_bind(stream).await {
+                        tracing::debug!("Relay bind from {} failed: {}", remote_addr, e);
+                    }
+                    return;
+                }
+                let accepted = match node.acceptor.accept(stream).await {
+                    Ok(tls) => node.establish(tls).await,
+                    Err(e) => Err(e.into()),
+                };
+                match accepted {
+                    Ok(conn) => node.register_peer(conn, remote_addr, Direction::Inbound, false, Some(tx)),
+                    Err(e) => {
+                        let _ = tx.send(SentinelEvent::HandshakeFailed {
+                            addr: remote_addr,
+                            direction: Direction::Inbound,
+                            error: e.to_string(),
+                        });
                     }
-                    node.peers.remove(&addr_str);
-                    let _ = tx.send(SentinelEvent::SystemLog(format!("Peer disconnected: {}", addr_str)));
                 }
             });
         }
     }
 
+    /// The event channel given to `run`, if the engine is running
+    pub fn event_sender(&self) -> Option<mpsc::UnboundedSender<SentinelEvent>> {
+        self.events.get().cloned()
+    }
+
+    /// Runs a background task, such as one of the `start_*` services, that `shutdown`
+    /// waits for
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    /// Like `spawn`, but the task is dropped as soon as `shutdown` starts. Used for dials,
+    /// which must not register a peer once the table has been drained.
+    pub fn spawn_until_shutdown<F>(&self, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let stopping = self.stopping.clone();
+        self.tasks.spawn(async move {
+            tokio::select! {
+                _ = stopping.cancelled() => {}
+                _ = task => {}
+            }
+        });
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.stopping.is_cancelled()
+    }
+
+    /// Resolves once `shutdown` has been called
+    pub(crate) async fn stopped(&self) {
+        self.stopping.cancelled().await
+    }
+
+    /// Waits for the next tick of a service interval. Returns false once the node is
+    /// shutting down, which ends the service.
+    pub(crate) async fn tick(&self, interval: &mut tokio::time::Interval) -> bool {
+        tokio::select! {
+            _ = self.stopping.cancelled() => false,
+            _ = interval.tick() => true,
+        }
+    }
+
+    /// Stops the node: the listener and services stop, every peer gets a `Disconnect` and
+    /// its queued messages before its connection closes, the mDNS service and the signaler
+    /// registration are withdrawn and storage is flushed. Returns once all of it has
+    /// finished, or with an error if tasks were still running after `timeout`.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<()> {
+        self.stopping.cancel();
+
+        let peer_ids: Vec<String> = self.peers.iter().map(|entry| entry.key().clone()).collect();
+        for peer_id in peer_ids {
+            self.disconnect(&peer_id, DisconnectReason::Local("Node shutting down".into()));
+        }
+
+        let service = self.mdns_service.lock().unwrap().take();
+        if let Some(fullname) = service {
+            if let Ok(status) = self.mdns.unregister(&fullname) {
+                let _ = tokio::time::timeout(timeout, status.recv_async()).await;
+            }
+        }
+        let _ = self.mdns.shutdown();
+
+        self.tasks.close();
+        let finished = tokio::time::timeout(timeout, self.tasks.wait()).await.is_ok();
+        self.db.flush_async().await?;
+        anyhow::ensure!(finished, "{} tasks still running after {:?}", self.tasks.len(), timeout);
+        Ok(())
+    }
+
+    /// Shared by inbound and outbound connections: takes the identity proven by the TLS
+    /// certificate and runs the handshake. Only the resulting `Authenticated` connection
+    /// can be registered as a peer.
+    async fn establish(&self, tls: TlsTransport<TokioTcpStream>) -> Result<Connection<PeerStream, Authenticated>> {
+        let peer_key = tls.peer_public_key().context("Peer presented no node certificate")?;
+        anyhow::ensure!(peer_key != self.identity.public_key_bytes(), "Dialed our own node");
+        let binding = tls.channel_binding().context("TLS channel binding unavailable")?;
+
+        let conn = Connection::new(Framed::new(tls, SentinelCodec::new()));
+        let hello = Hello {
+            identity: &self.identity,
+            node_name: &self.config.node_name,
+            listen_port: self.listen_port,
+            prekey: self.signed_prekey()?,
+        };
+        handshake::perform(conn, hello, &peer_key, &binding, self.config.handshake_timeout).await
+    }
+
+    /// Registers an authenticated connection under its node ID and spawns its reader and
+    /// writer tasks. A connection that loses the duplicate tie-break is closed instead.
+    /// For a `relayed` connection `remote_addr` is the relay's, so it is not recorded as
+    /// the peer's address.
+    fn register_peer(
+        self: &Arc<Self>,
+        conn: Connection<PeerStream, Authenticated>,
+        remote_addr: SocketAddr,
+        direction: Direction,
+        relayed: bool,
+        event_tx: Option<mpsc::UnboundedSender<SentinelEvent>>,
+    ) {
+        let (mut transport, peer) = conn.into_parts();
+        if self.is_shutting_down() {
+            self.tasks.spawn(async move { let _ = transport.close().await; });
+            return;
+        }
+        let conn_id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        let handle = ConnectionHandle::new();
+        let queue = Arc::new(SendQueue::new(self.config.queue_capacity, self.config.queue_policy, handle.clone()));
+        let peer_id = peer.user_id().to_string();
+        let addr = remote_addr.to_string();
+        let listen_addr = match direction {
+            Direction::Outbound => remote_addr,
+            Direction::Inbound => SocketAddr::new(remote_addr.ip(), peer.listen_port()),
+        };
+
+        if !relayed {
+            self.dht.update(PeerInfo {
+                node_id: peer_id.clone(),
+                address: listen_addr,
+                node_name: peer.node_name().to_string(),
+                last_seen: unix_now(),
+                prekey: Some(peer.prekey().clone()),
+            });
+            self.address_book.record_connected(&peer_id, peer.public_key(), peer.prekey(), listen_addr, peer.node_name(), unix_now());
+        }
+        let registration = self.peers.insert(PeerState {
+            queue: Arc::clone(&queue),
+            node_id: peer_id.clone(),
+            node_name: peer.node_name().to_string(),
+            public_key: peer.public_key().to_vec(),
+            prekey: peer.prekey().clone(),
+            last_seen: std::time::Instant::now(),
+            addr: addr.clone(),
+            addresses: Vec::new(),
+            listen_addr,
+            direction,
+            relayed,
+            rtt: None,
+            jitter: Duration::ZERO,
+            missed_pongs: 0,
+            pending_ping: None,
+            conn_id,
+            handle: handle.clone(),
+        }, &self.identity.public_key_bytes());
+
+        match registration {
+            Registration::Duplicate => {
+                self.tasks.spawn(async move { let _ = transport.close().await; });
+                return;
+            }
+            Registration::New => {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::PeerConnected { peer_id: peer_id.clone(), addr: addr.clone(), direction });
+                }
+            }
+            Registration::Replaced => {}
+        }
+
+        // `shutdown` may have drained the table between the check above and the insert
+        if self.is_shutting_down() {
+            self.disconnect(&peer_id, DisconnectReason::Local("Node shutting down".into()));
+        }
+
+        let (mut sink, mut stream_in) = transport.split();
+
+        // Outbound Worker (Library Internal)
+        let writer = handle.clone();
+        self.tasks.spawn(async move {
+            loop {
+                let msg = tokio::select! {
+                    biased;
+                    _ = writer.closed() => break,
+                    msg = queue.pop() => msg,
+                };
+                tokio::select! {
+                    _ = writer.closed() => break,
+                    sent = sink.send(msg) => if sent.is_err() { return; },
+                }
+            }
+            // Flush what was queued before the close (e.g. a Disconnect), then send
+            // close_notify and FIN. A stalled peer gets CLOSE_TIMEOUT, not forever.
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+                while let Some(msg) = queue.try_pop() {
+                    sink.feed(msg).await?;
+                }
+                sink.close().await
+            }).await;
+        });
+
+        // Inbound Message Loop
+        let node = Arc::clone(self);
+        self.tasks.spawn(async move {
+            let reason = loop {
+                let next = tokio::select! {
+                    _ = handle.closed() => break handle.reason().unwrap_or(DisconnectReason::ConnectionLost),
+                    next = stream_in.next() => next,
+                };
+                let Some(Ok(msg)) = next else { break DisconnectReason::ConnectionLost };
+                // Process protocol logic; a peer that fails verification is dropped
+                match node.clone().handle_incoming_message(msg, peer_id.clone()).await {
+                    // Emit high-level event for UI, only once the message is verified
+                    Ok(Some(event)) => {
+                        if let Some(tx) = &event_tx {
+                            let _ = tx.send(event);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => break DisconnectReason::ProtocolViolation(e.to_string()),
+                }
+            };
+            // Stops the writer too, if the connection ended on our side of the stream
+            handle.close(reason);
+            let reason = handle.reason().unwrap_or(DisconnectReason::ConnectionLost);
+            node.peers.remove_connection(&peer_id, conn_id);
+            // A replaced connection hands over to its successor; the peer is still connected
+            if reason != DisconnectReason::Replaced {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::PeerDisconnected { peer_id, reason });
+                }
+            }
+        });
+    }
+
+    /// Mints a fresh certificate from the node key and serves it to all new connections.
+    /// Not available when the certificate comes from configured files.
+    pub fn rotate_certificate(&self) -> Result<()> {
+        anyhow::ensure!(self.config.cert_path.is_none(), "The certificate is configured from files");
+        let (certs, key) = rotate_node_cert(&self.identity, &self.data_dir)?;
+        self.acceptor.set_certificate(certs.clone(), key.clone_key())?;
+        self.connector.set_certificate(certs, key);
+        Ok(())
+    }
+
     pub async fn is_local_peer(&self, target: SocketAddr) -> bool {
         if let Some(my_public) = *self.public_addr.read().await {
             return target.ip() == my_public.ip();
@@ -156,122 +498,518 @@ impl SentinelNode {
         false
     }
 
-    pub async fn discover_and_set_public_ip(&self) -> Result<()> {
-        match FighterSocket::discover_public_ip(self.listen_port).await {
-            Ok(addr) => {
-                let mut lock = self.public_addr.write().await;
-                *lock = Some(addr);
-                Ok(())
-            }
-            Err(e) => Err(anyhow::anyhow!("STUN discovery failed: {}", e)),
-        }
+    /// Asks the configured STUN servers for our public address and NAT type, and keeps both
+    pub async fn discover_and_set_public_ip(&self) -> Result<StunReport> {
+        anyhow::ensure!(!self.config.stun_servers.is_empty(), "No STUN servers configured");
+        let report = stun::probe(&self.config.stun_servers, self.listen_port).await.context("STUN discovery failed")?;
+        *self.public_addr.write().await = Some(report.public_addr);
+        *self.nat_type.write().await = Some(report.nat_type);
+        Ok(report)
     }
 
-    pub fn sign_and_send(&self, tx: &mpsc::UnboundedSender<SentinelMessage>, mut msg: SentinelMessage) {
+    pub fn sign_message(&self, mut msg: SentinelMessage) -> SentinelMessage {
         msg.public_key = self.identity.public_key_bytes();
         msg.signature = self.identity.sign(&msg.sig_hash());
-        let _ = tx.send(msg);
+        msg
+    }
+
+    /// Signs `msg` and queues it on `queue`. Returns whether it was enqueued; the queue's
+    /// overflow policy decides what happens when it is full.
+    pub fn sign_and_send(&self, queue: &SendQueue, msg: SentinelMessage) -> bool {
+        queue.push(self.sign_message(msg))
+    }
+
+    /// Signs `content` once and sends it to every connected peer, which relay it onwards.
+    /// Returns the number of peers it was enqueued for.
+    pub fn broadcast(&self, content: MessageContent) -> usize {
+        let msg = self.sign_message(SentinelMessage::new(self.identity.node_id(), content));
+        self.peers.iter().filter(|entry| entry.value().queue.push(msg.clone())).count()
+    }
+
+    /// Floods a relayed message to every peer except the one it came from and its author
+    fn forward(&self, msg: &SentinelMessage, from: &str) {
+        let Some(ttl) = relay_ttl(msg.ttl) else { return };
+        let mut relayed = msg.clone();
+        relayed.ttl = ttl;
+        for entry in self.peers.iter() {
+            let peer = entry.value();
+            if peer.node_id != from && peer.node_id != msg.sender {
+                peer.queue.push(relayed.clone());
+            }
+        }
+    }
+
+    /// Drops the connection to `node_id`. Unless the peer asked for it, it is told why
+    /// with a `Disconnect` before the connection closes.
+    pub fn disconnect(&self, node_id: &str, reason: DisconnectReason) {
+        let Some(peer) = self.peers.remove(node_id) else { return };
+        if !matches!(reason, DisconnectReason::Remote(_)) {
+            let goodbye = SentinelMessage::new(self.identity.node_id(), MessageContent::Disconnect(reason.to_string()));
+            self.sign_and_send(&peer.queue, goodbye);
+        }
+        peer.handle.close(reason);
+    }
+
+    /// Signs and sends `content` to a single connected node. Fails if the node is not
+    /// currently connected, its connection has already closed or its queue rejected the message.
+    pub fn send_to(&self, node_id: &str, content: MessageContent) -> Result<()> {
+        let peer = self.peers.get(node_id).with_context(|| format!("Peer {} is not connected", node_id))?;
+        let msg = SentinelMessage::new(self.identity.node_id(), content);
+        anyhow::ensure!(self.sign_and_send(&peer.queue, msg), "Message to {} was not enqueued", node_id);
+        Ok(())
+    }
+
+    /// Encrypts `text` end-to-end for `node_id` and sends it as a `Direct` message. Nodes
+    /// that are not directly connected are reached by flooding it through the mesh, which the
+    /// returned `Delivery` tells apart. The first message to a node needs its prekey, learned
+    /// from a handshake, gossip or a DHT lookup.
+    pub fn send_direct(&self, node_id: &str, text: &str) -> Result<Delivery> {
+        let aad = direct_aad(&self.identity.node_id(), node_id);
+        let (header, ciphertext) = self.with_sessions(node_id, |sessions| {
+            if sessions.is_empty() {
+                let prekey = self.peer_prekey(node_id).with_context(|| format!("No prekey known for {}; look it up first", node_id))?;
+                let peer_key = hex::decode(node_id).context("Invalid node ID")?;
+                sessions.push(Session::initiate(&self.identity, &peer_key, &prekey.key)?);
+            }
+            sessions[0].seal(text.as_bytes(), &aad)
+        })?;
+        let content = MessageContent::Direct { recipient: node_id.to_string(), header, ciphertext };
+
+        if self.peers.contains(node_id) {
+            return self.send_to(node_id, content).map(|()| Delivery::Direct);
+        }
+        match self.broadcast(content) {
+            0 => Err(anyhow::anyhow!("No peers to relay through to {}", node_id)),
+            peers => Ok(Delivery::Relayed { peers }),
+        }
+    }
+
+    /// Decrypts a `Direct` message addressed to this node. Each message key is used once,
+    /// so a message can only be opened the first time it is seen. A message that opens in
+    /// none of our sessions may start a new one with one of our prekeys.
+    pub fn open_direct(&self, msg: &SentinelMessage) -> Result<String> {
+        let MessageContent::Direct { recipient, header, ciphertext } = &msg.content else {
+            return Err(anyhow::anyhow!("Not a direct message"));
+        };
+        let aad = direct_aad(&msg.sender, recipient);
+        let init = Header::from_bytes(header).context("Malformed ratchet header")?.init;
+        let plaintext = self.with_sessions(&msg.sender, |sessions| {
+            for i in 0..sessions.len() {
+                if let Ok(plaintext) = sessions[i].open(header, ciphertext, &aad) {
+                    let session = sessions.remove(i);
+                    sessions.insert(0, session);
+                    return Ok(plaintext);
+                }
+            }
+            let init = init.context("Decryption failed")?;
+            anyhow::ensure!(!sessions.iter().any(|s| s.accepted_from(&init)), "Replayed session start");
+            let prekey = self.own_prekey(&init.prekey)?.context("Message sealed to an unknown or expired prekey")?;
+            let peer_key = hex::decode(&msg.sender).context("Invalid node ID")?;
+            let mut session = Session::respond(&self.identity, &prekey, &peer_key, &init)?;
+            let plaintext = session.open(header, ciphertext, &aad)?;
+            sessions.insert(0, session);
+            sessions.truncate(MAX_SESSIONS);
+            Ok(plaintext)
+        })?;
+        String::from_utf8(plaintext).context("Direct message is not valid UTF-8")
+    }
+
+    /// Runs `f` on the ratchet sessions with `node_id`, newest first, and persists them to
+    /// the `sessions` tree if it succeeds
+    fn with_sessions<R>(&self, node_id: &str, f: impl FnOnce(&mut Vec<Session>) -> Result<R>) -> Result<R> {
+        let tree = self.db.open_tree("sessions")?;
+        let mut sessions = self.sessions.entry(node_id.to_string()).or_try_insert_with(|| -> Result<_> {
+            // State that does not decode, such as sessions from before X3DH seeding, is dropped
+            Ok(tree.get(node_id)?.and_then(|bytes| bincode::deserialize(&bytes).ok()).unwrap_or_default())
+        })?;
+        let result = f(&mut sessions)?;
+        tree.insert(node_id, bincode::serialize(&*sessions)?)?;
+        Ok(result)
+    }
+
+    /// Our current prekey, signed for the handshake and gossip. It is replaced every
+    /// `PREKEY_ROTATION_SECS`, and secrets older than two periods are deleted, after which
+    /// first messages sealed to them can no longer be opened, even with `identity.key`.
+    pub fn signed_prekey(&self) -> Result<SignedPreKey> {
+        let tree = self.db.open_tree("prekeys")?;
+        let now = unix_now();
+        let mut current: Option<PreKey> = None;
+        for item in tree.iter() {
+            let (key, value) = item?;
+            let prekey = PreKey::from_bytes(&value)?;
+            if prekey.created.saturating_add(2 * PREKEY_ROTATION_SECS) < now {
+                tree.remove(key)?;
+            } else if current.as_ref().is_none_or(|c| prekey.created > c.created) {
+                current = Some(prekey);
+            }
+        }
+        let prekey = match current {
+            Some(prekey) if prekey.created + PREKEY_ROTATION_SECS > now => prekey,
+            _ => {
+                let prekey = PreKey::generate(now);
+                tree.insert(prekey.public_key(), prekey.to_bytes())?;
+                prekey
+            }
+        };
+        Ok(SignedPreKey { key: prekey.public_key().to_vec(), created: prekey.created, signature: prekey.sign(&self.identity) })
+    }
+
+    fn own_prekey(&self, public_key: &[u8]) -> Result<Option<PreKey>> {
+        let tree = self.db.open_tree("prekeys")?;
+        tree.get(public_key)?.map(|bytes| PreKey::from_bytes(&bytes)).transpose()
     }
 
+    /// Newest prekey signed by `node_id` that we have heard of, from its handshake, the DHT
+    /// or gossip
+    pub fn peer_prekey(&self, node_id: &str) -> Option<SignedPreKey> {
+        let owner = hex::decode(node_id).ok()?;
+        let known = [
+            self.peers.get(node_id).map(|peer| peer.prekey.clone()),
+            self.dht.get(node_id).and_then(|contact| contact.prekey),
+            self.address_book.get(node_id).and_then(|entry| entry.prekey),
+        ];
+        known.into_iter()
+            .flatten()
+            .filter(|prekey| verify_prekey(&owner, &prekey.key, prekey.created, &prekey.signature))
+            .max_by_key(|prekey| prekey.created)
+    }
+
+    /// Pings every peer every `heartbeat_interval`. A peer that leaves `max_missed_pongs`
+    /// pings in a row unanswered is evicted.
     pub async fn start_heartbeat_service(self: Arc<Self>) {
-        let mut interval = tokio::time::interval(Duration::from_secs(20));
+        let mut interval = tokio::time::interval(self.config.heartbeat_interval);
         loop {
-            interval.tick().await;
-            let ping = SentinelMessage::new(self.identity.node_id(), MessageContent::Chat("PING".into()));
-            for entry in self.peers.iter() {
-                self.sign_and_send(&entry.value().tx, ping.clone());
+            if !self.tick(&mut interval).await { return; }
+            let mut dead = Vec::new();
+            for mut entry in self.peers.iter_mut() {
+                let peer = entry.value_mut();
+                if peer.pending_ping.is_some() {
+                    peer.missed_pongs += 1;
+                    if peer.missed_pongs >= self.config.max_missed_pongs {
+                        dead.push(peer.node_id.clone());
+                        continue;
+                    }
+                }
+                let nonce = rand::random();
+                peer.pending_ping = Some((nonce, std::time::Instant::now()));
+                let ping = SentinelMessage::new(self.identity.node_id(), MessageContent::Ping { nonce });
+                self.sign_and_send(&peer.queue, ping);
+            }
+            for node_id in dead {
+                self.disconnect(&node_id, DisconnectReason::Unresponsive);
             }
-            
-            self.peers.retain(|_, state| state.last_seen.elapsed() < Duration::from_secs(60));
         }
     }
 
-    pub async fn dial_peer(self: Arc<Self>, addr: String) -> Result<()> {
+    /// Dials `addr` over mutual TLS. With `expected_id` set, the connection is refused unless
+    /// the remote certificate carries exactly that node key. Events of the resulting
+    /// connection, including a failed handshake, go to `event_tx`.
+    pub async fn dial_peer(
+        self: Arc<Self>,
+        addr: String,
+        expected_id: Option<String>,
+        event_tx: Option<mpsc::UnboundedSender<SentinelEvent>>,
+    ) -> Result<()> {
+        anyhow::ensure!(!self.is_shutting_down(), "Node is shutting down");
+        let expected_key = expected_id.as_deref()
+            .map(|id| hex::decode(id).context("Invalid node ID"))
+            .transpose()?;
         let target_addr: SocketAddr = addr.to_socket_addrs()?.next().context("Address resolution failed")?;
 
-        if target_addr.port() == self.listen_port || self.peers.contains_key(&addr) {
+        let already_connected = self.peers.contains_addr(&target_addr.to_string())
+            || expected_id.as_deref().is_some_and(|id| self.peers.contains(id));
+        if self.is_own_listener(&target_addr) || already_connected {
             return Ok(());
         }
 
-        let local_bind = SocketAddr::from(([0, 0, 0, 0], self.listen_port));
-        let fighter = FighterSocket::create_war_ready(local_bind)
-            .or_else(|_| FighterSocket::create_war_ready(SocketAddr::from(([0, 0, 0, 0], 0))))?;
+        // Leave from the listen port where possible, in the target's address family
+        let fighter = FighterSocket::create_war_ready(FighterSocket::unspecified(&target_addr, self.listen_port))
+            .or_else(|_| FighterSocket::create_war_ready(FighterSocket::unspecified(&target_addr, 0)))?;
 
-        match fighter.connect(&target_addr.into()) {
-            Ok(_) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.raw_os_error() == Some(115) => {}
-            Err(e) => return Err(anyhow::anyhow!("Fighter punch failed: {}", e)),
-        }
+        let tokio_stream = FighterSocket::connect(fighter, target_addr).await?;
 
-        let std_stream: std::net::TcpStream = fighter.into();
-        let tokio_stream = TokioTcpStream::from_std(std_stream)?;
-        tokio_stream.writable().await?;
+        let connected = match self.connector.connect(SENTINEL_SERVER_NAME, tokio_stream, expected_key).await {
+            Ok(tls) => self.establish(tls).await,
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok(conn) => {
+                self.register_peer(conn, target_addr, Direction::Outbound, false, event_tx);
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::HandshakeFailed {
+                        addr: target_addr,
+                        direction: Direction::Outbound,
+                        error: e.to_string(),
+                    });
+                }
+                Err(e)
+            }
+        }
+    }
 
-        let connector = SentinelConnector::new();
-        let tls = connector.connect("sentinel-node.local", tokio_stream).await?;
+    /// Dials a node known at several addresses, Happy Eyeballs style (RFC 8305): families
+    /// alternate after the preferred address, and each attempt gets a head start before the
+    /// next one joins. The first handshake to complete wins and the others are dropped.
+    pub async fn dial_any(
+        self: Arc<Self>,
+        addrs: &[SocketAddr],
+        expected_id: Option<String>,
+        event_tx: Option<mpsc::UnboundedSender<SentinelEvent>>,
+    ) -> Result<()> {
+        let mut queue = eyeballs::interleave_families(addrs).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        let start = |addr: SocketAddr| Arc::clone(&self).dial_peer(addr.to_string(), expected_id.clone(), event_tx.clone());
+        attempts.extend(queue.next().map(start));
 
-        let (mut sink, mut stream) = Framed::new(tls, SentinelCodec::new()).split();
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        while !attempts.is_empty() {
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(()) => return Ok(()),
+                    Err(e) => {
+                        last_error = Some(e);
+                        attempts.extend(queue.next().map(start));
+                    }
+                },
+                _ = tokio::time::sleep(eyeballs::CONNECTION_ATTEMPT_DELAY), if queue.len() > 0 => {
+                    attempts.extend(queue.next().map(start));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No address to dial")))
+    }
 
-        self.peers.insert(addr.clone(), PeerState {
-            tx: tx.clone(),
-            node_id: "pending".into(),
-            node_name: "Outbound".into(),
-            public_key: None,
-            last_seen: std::time::Instant::now(),
-        });
+    /// Connects to a node behind NAT by TCP simultaneous open, as ordered by a signaler
+    /// `PunchCommand` sent to both ends. At `timestamp_ns` a burst of attempts leaves our
+    /// listen port; a SYN of the peer's that reaches our listener first is taken over
+    /// instead. The lower node ID then runs the TLS server side, and either way the peer
+    /// must prove the key behind `peer_id`. The outcome is reported as `PunchSucceeded`
+    /// or `PunchFailed`.
+    pub async fn punch_peer(
+        self: Arc<Self>,
+        peer_id: String,
+        target_addr: SocketAddr,
+        timestamp_ns: u64,
+        event_tx: Option<mpsc::UnboundedSender<SentinelEvent>>,
+    ) -> Result<()> {
+        anyhow::ensure!(!self.is_shutting_down(), "Node is shutting down");
+        anyhow::ensure!(peer_id != self.identity.node_id(), "Cannot punch through to our own node");
+        if self.peers.contains(&peer_id) {
+            return Ok(());
+        }
+        let target_addr = SocketAddr::new(target_addr.ip().to_canonical(), target_addr.port());
+        let accepts = punch::accepts_tls(&self.identity.node_id(), &peer_id);
 
-        let hs = SentinelMessage::new(self.identity.node_id(), MessageContent::Handshake {
-            public_key: self.identity.public_key_bytes(),
-            node_name: "Sentinel-Node".into(),
-        });
-        self.sign_and_send(&tx, hs);
+        let (taken_tx, taken_rx) = oneshot::channel();
+        self.punches.insert(target_addr, taken_tx);
+        let burst = async {
+            tokio::time::sleep(punch::delay_until(timestamp_ns, punch::now_ns())).await;
+            punch::connect_burst(self.listen_port, target_addr).await
+        };
+        let stream = tokio::select! {
+            _ = self.stopping.cancelled() => Err(anyhow::anyhow!("Node is shutting down")),
+            Ok(stream) = taken_rx => Ok(stream),
+            stream = burst => stream,
+        };
+        self.punches.remove(&target_addr);
 
-        let addr_io = addr.clone();
-        tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if sink.send(msg).await.is_err() { break; }
+        let connected = match stream {
+            Ok(stream) => self.rendezvous(stream, &peer_id).await,
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok((conn, direction)) => {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::PunchSucceeded { peer_id, addr: target_addr });
+                }
+                self.register_peer(conn, target_addr, direction, false, event_tx);
+                Ok(())
             }
-        });
+            Err(e) => {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::PunchFailed { peer_id: peer_id.clone(), addr: target_addr, error: e.to_string() });
+                }
+                // Fall back to a relay. Only one end asks, or the signaler would set up two sessions.
+                if accepts && self.config.signaler_addr.is_some() {
+                    let request = SignalingMessage::RelayRequest { target_id: peer_id };
+                    let _ = self.signaler_tx.try_send(SentinelMessage::new_signal(self.identity.node_id(), request));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Secures a stream that both ends opened towards each other, as after a punch or
+    /// through a relay. Neither is the natural TLS client, so the lower node ID takes the
+    /// server side; either way the peer must prove the key behind `peer_id`.
+    async fn rendezvous(&self, stream: TokioTcpStream, peer_id: &str) -> Result<(Connection<PeerStream, Authenticated>, Direction)> {
+        let expected_key = hex::decode(peer_id).context("Invalid node ID")?;
+        if punch::accepts_tls(&self.identity.node_id(), peer_id) {
+            let tls = self.acceptor.accept(stream).await?;
+            anyhow::ensure!(tls.peer_public_key().as_ref() == Some(&expected_key), "Connection reached a different node");
+            Ok((self.establish(tls).await?, Direction::Inbound))
+        } else {
+            let tls = self.connector.connect(SENTINEL_SERVER_NAME, stream, Some(expected_key)).await?;
+            Ok((self.establish(tls).await?, Direction::Outbound))
+        }
+    }
 
-        let node_inner = Arc::clone(&self);
-        tokio::spawn(async move {
-            while let Some(Ok(msg)) = stream.next().await {
-                let _ = node_inner.clone().handle_incoming_message(msg, addr_io.clone()).await;
+    /// Connects to `peer_id` through the relay at `relay_addr` with the token of a
+    /// `RelayOffer`. The relay only splices bytes: TLS and the handshake run end to end
+    /// as for a punched connection, so the session is still pinned to the peer's key.
+    /// The outcome is reported as `Relayed` or `RelayFailed`.
+    pub async fn relay_peer(
+        self: Arc<Self>,
+        relay_addr: SocketAddr,
+        peer_id: String,
+        token: Vec<u8>,
+        event_tx: Option<mpsc::UnboundedSender<SentinelEvent>>,
+    ) -> Result<()> {
+        anyhow::ensure!(!self.is_shutting_down(), "Node is shutting down");
+        if self.peers.contains(&peer_id) {
+            return Ok(());
+        }
+        let connected = async {
+            let mut framed = Framed::new(TokioTcpStream::connect(relay_addr).await?, SentinelCodec::new());
+            framed.send(SentinelMessage::new_signal(self.identity.node_id(), SignalingMessage::RelayBind { token })).await?;
+            self.rendezvous(framed.into_inner(), &peer_id).await
+        }.await;
+        match connected {
+            Ok((conn, direction)) => {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::Relayed { peer_id, relay: relay_addr });
+                }
+                self.register_peer(conn, relay_addr, direction, true, event_tx);
+                Ok(())
             }
-            node_inner.peers.remove(&addr_io);
+            Err(e) => {
+                if let Some(tx) = &event_tx {
+                    let _ = tx.send(SentinelEvent::RelayFailed { peer_id, relay: relay_addr, error: e.to_string() });
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Asks `relay_id`, a connected peer running a relay, to splice us with `target_id`.
+    /// The target must ask the same relay for us within `RELAY_REQUEST_TIMEOUT`. A relay
+    /// that declines, or is not connected to the target, does not answer.
+    pub fn relay_via(&self, relay_id: &str, target_id: &str) -> Result<()> {
+        self.send_to(relay_id, MessageContent::RelayRequest { target: target_id.to_string() })?;
+        let now = Instant::now();
+        self.relay_requests.retain(|_, (_, expires)| *expires > now);
+        self.relay_requests.insert(target_id.to_string(), (relay_id.to_string(), now + RELAY_REQUEST_TIMEOUT));
+        Ok(())
+    }
+
+    /// Consumes our pending request for `target` if `relay_id` is the relay it went to
+    fn take_relay_request(&self, relay_id: &str, target: &str) -> bool {
+        self.relay_requests
+            .remove_if(target, |_, (relay, expires)| relay == relay_id && *expires > Instant::now())
+            .is_some()
+    }
+
+    /// Sets up a relay session between two of our peers and hands each end its token, once
+    /// both have asked for the other
+    fn offer_relay(&self, requester: &str, target: &str) {
+        let Some(relay) = &self.relay else { return };
+        if requester == target || !self.peers.contains(target) {
+            return;
+        }
+        let now = Instant::now();
+        self.relay_asks.retain(|_, expires| *expires > now);
+        if self.relay_asks.remove(&(target.to_string(), requester.to_string())).is_none() {
+            self.relay_asks.insert((requester.to_string(), target.to_string()), now + RELAY_REQUEST_TIMEOUT);
+            return;
+        }
+        let Some((requester_token, target_token)) = relay.allocate() else { return };
+        let offer = |peer_id: &str, token: RelayToken| MessageContent::Signal(SignalingMessage::RelayOffer {
+            peer_id: peer_id.to_string(),
+            token: token.to_vec(),
         });
+        let _ = self.send_to(target, offer(requester, target_token));
+        let _ = self.send_to(requester, offer(target, requester_token));
+    }
+
+    async fn is_relay_bind(&self, stream: &TokioTcpStream) -> bool {
+        let mut magic = [0u8; MAGIC_LEN];
+        let peeked = tokio::time::timeout(self.config.handshake_timeout, stream.peek(&mut magic)).await;
+        matches!(peeked, Ok(Ok(MAGIC_LEN))) && magic == MAGIC
+    }
 
+    async fn serve_relay_bind(&self, stream: TokioTcpStream) -> Result<()> {
+        let relay = self.relay.as_ref().context("Not a relay")?;
+        let mut framed = Framed::new(stream, SentinelCodec::new());
+        let first = tokio::time::timeout(self.config.handshake_timeout, framed.next()).await
+            .context("Relay bind timed out")?
+            .context("Connection closed before the relay bind")??;
+        let MessageContent::Signal(SignalingMessage::RelayBind { token }) = first.content else {
+            anyhow::bail!("Expected a relay bind");
+        };
+        let parts = framed.into_parts();
+        relay.join(&token, parts.io, parts.read_buf).await?;
         Ok(())
     }
 
-    pub async fn start_signaler_client(
-        self: Arc<Self>, 
-        signaler_addr: String, 
-        mut signaler_outbound: mpsc::UnboundedReceiver<SentinelMessage>
-    ) {
-        loop {
-            if let Ok(stream) = tokio::net::TcpStream::connect(&signaler_addr).await {
-                let mut framed = Framed::new(stream, SentinelCodec::new());
-                let my_id = self.identity.node_id();
-                
-                let reg = SentinelMessage::new_signal(my_id.clone(), SignalingMessage::Register {
-                    node_id: my_id,
-                    public_key: self.identity.public_key_bytes(),
-                    signature: vec![], 
-                });
+    /// Whether `addr` points back at one of our own listeners
+    fn is_own_listener(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        addr.port() == self.listen_port
+            && (ip.is_loopback() || ip.is_unspecified() || self.config.listen_addrs.iter().any(|l| l.ip() == ip))
+    }
 
-                if framed.send(reg).await.is_ok() {
+    /// Keeps a connection to the configured signaler, reconnecting as needed. Returns at
+    /// once if no signaler is configured.
+    pub async fn start_signaler_client(self: Arc<Self>, mut signaler_outbound: mpsc::Receiver<SentinelMessage>) {
+        let Some(signaler_addr) = self.config.signaler_addr.clone() else { return };
+        while !self.is_shutting_down() {
+            let connected = tokio::select! {
+                _ = self.stopping.cancelled() => return,
+                connected = self.connect_signaler(&signaler_addr) => connected,
+            };
+            if let Err(e) = &connected {
+                tracing::debug!("Signaler {} unreachable: {:#}", signaler_addr, e);
+            }
+            if let Ok((relay_addr, mut framed)) = connected {
+                let registered = match tokio::time::timeout(self.config.handshake_timeout, self.register_with_signaler(&mut framed)).await {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => { tracing::warn!("Signaler registration failed: {}", e); false }
+                    Err(_) => { tracing::warn!("Signaler registration timed out"); false }
+                };
+
+                if registered {
                     let (mut sink, mut stream) = framed.split();
                     loop {
                         tokio::select! {
+                            _ = self.stopping.cancelled() => {
+                                let bye = SentinelMessage::new_signal(self.identity.node_id(), SignalingMessage::Unregister {
+                                    node_id: self.identity.node_id(),
+                                });
+                                let _ = sink.send(bye).await;
+                                let _ = sink.close().await;
+                                return;
+                            }
                             Some(out_msg) = signaler_outbound.recv() => {
                                 if sink.send(out_msg).await.is_err() { break; }
                             }
                             Some(Ok(msg)) = stream.next() => {
-                                if let MessageContent::Signal(SignalingMessage::PeerResponse { public_addr, .. }) = msg.content {
-                                    let node = Arc::clone(&self);
-                                    tokio::spawn(async move { let _ = node.dial_peer(public_addr.to_string()).await; });
+                                let node = Arc::clone(&self);
+                                let events = node.event_sender();
+                                match msg.content {
+                                    MessageContent::Signal(SignalingMessage::PeerResponse { peer_id, public_addr }) => {
+                                        self.spawn_until_shutdown(async move { let _ = node.dial_peer(public_addr.to_string(), Some(peer_id), events).await; });
+                                    }
+                                    MessageContent::Signal(SignalingMessage::PunchCommand { peer_id, target_addr, timestamp_ns }) => {
+                                        self.spawn(async move { let _ = node.punch_peer(peer_id, target_addr, timestamp_ns, events).await; });
+                                    }
+                                    // The signaler relays on its own address
+                                    MessageContent::Signal(SignalingMessage::RelayOffer { peer_id, token }) => {
+                                        self.spawn(async move { let _ = node.relay_peer(relay_addr, peer_id, token, events).await; });
+                                    }
+                                    _ => {}
                                 }
                             }
                             else => break,
@@ -279,79 +1017,277 @@ impl SentinelNode {
                     }
                 }
             }
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            tokio::select! {
+                _ = self.stopping.cancelled() => return,
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            }
+        }
+    }
+
+    /// Connects from the listen port where possible, so the address the signaler records
+    /// is the NAT mapping that punched connections will reuse. Without a configured
+    /// `signaler_key`, the key the signaler shows first is pinned in the `signaler_pins`
+    /// tree, and a signaler proving any other key is refused from then on.
+    async fn connect_signaler(&self, addr: &str) -> Result<(SocketAddr, PeerStream)> {
+        let target = tokio::net::lookup_host(addr).await?.next().context("Signaler address resolution failed")?;
+        let stream = match FighterSocket::create_war_ready(FighterSocket::unspecified(&target, self.listen_port)) {
+            Ok(socket) => FighterSocket::connect(socket, target).await?,
+            Err(_) => TokioTcpStream::connect(target).await?,
+        };
+        let pins = self.db.open_tree("signaler_pins")?;
+        let expected_key = match &self.config.signaler_key {
+            Some(key) => Some(hex::decode(key)?),
+            None => pins.get(addr)?.map(|key| key.to_vec()),
+        };
+        let pinned = expected_key.as_deref().map(hex::encode);
+        let tls = tokio::time::timeout(self.config.handshake_timeout, self.connector.connect(SENTINEL_SERVER_NAME, stream, expected_key))
+            .await
+            .context("TLS handshake with the signaler timed out")?
+            .inspect_err(|e| {
+                if let Some(key) = &pinned {
+                    tracing::warn!("Signaler {} did not prove the pinned key {}: {:#}", addr, key, e);
+                }
+            })?;
+        if self.config.signaler_key.is_none() && !pins.contains_key(addr)? {
+            let key = tls.peer_public_key().context("Signaler presented no certificate")?;
+            tracing::warn!(
+                "No signaler key configured: trusting {} at {} on first use and pinning it. Set signaler_key to choose the key yourself.",
+                hex::encode(&key), addr,
+            );
+            pins.insert(addr, key)?;
         }
+        Ok((target, Framed::new(tls, SentinelCodec::new())))
+    }
+
+    /// Claims our directory entry and answers the signaler's challenge with a signature,
+    /// so no other node can take over our node ID
+    async fn register_with_signaler(&self, framed: &mut PeerStream) -> Result<()> {
+        let node_id = self.identity.node_id();
+        let register = |signature| SentinelMessage::new_signal(node_id.clone(), SignalingMessage::Register {
+            node_id: node_id.clone(),
+            public_key: self.identity.public_key_bytes(),
+            signature,
+        });
+        framed.send(register(vec![])).await?;
+        let reply = framed.next().await.context("Signaler closed the connection")??;
+        let nonce = match reply.content {
+            MessageContent::Signal(SignalingMessage::Challenge { nonce }) => nonce,
+            MessageContent::Signal(SignalingMessage::Error(e)) => anyhow::bail!("Signaler refused us: {}", e),
+            _ => anyhow::bail!("Expected a registration challenge"),
+        };
+        let signature = self.identity.sign(&SignalingMessage::registration_transcript(&node_id, &nonce));
+        framed.send(register(signature)).await?;
+        Ok(())
     }
 
     pub fn print_history(&self) -> Result<()> {
         let tree = self.db.open_tree("messages")?;
         for item in tree.iter().values().rev().take(10) { let item = item?;
             if let Ok(msg) = SentinelMessage::from_bytes(&item) {
-                if let MessageContent::Chat(text) = msg.content {
-                    println!("[{}] {}", msg.sender, text);
+                match msg.content {
+                    MessageContent::Chat(text) => println!("[{}] {}", msg.sender, text),
+                    // Only the arrival of a DM is stored, never its ciphertext
+                    MessageContent::Direct { .. } => println!("[DM {}] <sealed>", msg.sender),
+                    _ => {}
                 }
             }
         }
         Ok(())
     }
 
-    pub(crate) fn handle_incoming_message(self: Arc<Self>, msg: SentinelMessage, addr: String) -> BoxFuture<'static, Result<()>> {
+    /// Verifies and processes a message from `peer_id`, returning the event to surface, if any
+    pub(crate) fn handle_incoming_message(self: Arc<Self>, msg: SentinelMessage, peer_id: String) -> BoxFuture<'static, Result<Option<SentinelEvent>>> {
         let node = self.clone();
         async move {
-            if let Some(mut peer) = node.peers.get_mut(&addr) {
+            node.verify_peer_message(&msg, &peer_id)?;
+
+            if let Some(mut peer) = node.peers.get_mut(&peer_id) {
                 peer.last_seen = std::time::Instant::now();
             }
 
+            // Our own broadcast, echoed back by the mesh
+            if msg.sender == node.identity.node_id() { return Ok(None); }
+
             {
                 let mut seen = node.seen_messages.lock().await;
-                if seen.contains(&msg.id) { return Ok(()); }
+                if seen.contains(&msg.id) { return Ok(None); }
                 seen.put(msg.id, ());
             }
 
-            if !msg.signature.is_empty() && !NodeIdentity::verify(&msg.sig_hash(), &msg.signature, &msg.public_key) {
-                return Ok(());
-            }
-
-            match &msg.content { 
-                MessageContent::Handshake { public_key, node_name } => {
-                    if let Some(mut peer) = node.peers.get_mut(&addr) {
-                        peer.node_id = msg.sender.clone();
-                        peer.node_name = node_name.clone(); 
-                        peer.public_key = Some(public_key.clone()); 
-                    }
+            let event = match &msg.content { 
+                MessageContent::Handshake { .. } | MessageContent::HandshakeProof { .. } => {
+                    return Err(anyhow::anyhow!("Unexpected handshake from established peer {}", peer_id));
                 }
-                MessageContent::Chat(text) if text != "PING" => {
+                MessageContent::Chat(text) => {
+                    node.forward(&msg, &peer_id);
                     let _ = node.persist_message(&msg);
+                    Some(SentinelEvent::ChatMessage { sender: msg.sender.clone(), text: text.clone() })
                 }
-                _ => {}
-            }
-            Ok(())
+                MessageContent::Direct { recipient, .. } if *recipient == node.identity.node_id() => {
+                    let _ = node.persist_message(&msg);
+                    Some(match node.open_direct(&msg) {
+                        Ok(text) => SentinelEvent::DirectMessage { sender: msg.sender.clone(), text },
+                        Err(e) => SentinelEvent::DirectMessageFailed { sender: msg.sender.clone(), error: e.to_string() },
+                    })
+                }
+                MessageContent::Direct { .. } => {
+                    node.forward(&msg, &peer_id);
+                    None
+                }
+                MessageContent::Ping { nonce } => {
+                    let _ = node.send_to(&peer_id, MessageContent::Pong { nonce: *nonce });
+                    None
+                }
+                MessageContent::Pong { nonce } => {
+                    if let Some(mut peer) = node.peers.get_mut(&peer_id) {
+                        if let Some((expected, sent_at)) = peer.pending_ping {
+                            if expected == *nonce {
+                                peer.record_rtt(sent_at.elapsed());
+                            }
+                        }
+                    }
+                    None
+                }
+                MessageContent::PeerDiscovery(list) => {
+                    let own_id = node.identity.node_id();
+                    for info in list.iter().filter(|info| info.node_id != own_id) {
+                        node.address_book.merge(info.clone(), &peer_id, unix_now());
+                    }
+                    None
+                }
+                MessageContent::FindNode { .. } | MessageContent::FindValue { .. }
+                | MessageContent::Nodes { .. } | MessageContent::Value { .. } => {
+                    dht::handle_message(&node, &msg, &peer_id);
+                    None
+                }
+                MessageContent::Disconnect(reason) => {
+                    node.disconnect(&peer_id, DisconnectReason::Remote(reason.clone()));
+                    None
+                }
+                MessageContent::RelayRequest { target } => {
+                    node.offer_relay(&peer_id, target);
+                    None
+                }
+                // A peer relaying for us, reached where we already reach it, answering our request
+                MessageContent::Signal(SignalingMessage::RelayOffer { peer_id: other, token }) => {
+                    let relay_addr = node.peers.get(&peer_id).filter(|p| !p.relayed).map(|p| p.listen_addr);
+                    match relay_addr {
+                        Some(relay_addr) if node.take_relay_request(&peer_id, other) => {
+                            let (relayed, other, token, events) = (Arc::clone(&node), other.clone(), token.clone(), node.event_sender());
+                            node.spawn(async move { let _ = relayed.relay_peer(relay_addr, other, token, events).await; });
+                        }
+                        _ => tracing::warn!("Ignoring unsolicited relay offer from {} for {}", peer_id, other),
+                    }
+                    None
+                }
+                _ => None,
+            };
+            Ok(event)
         }.boxed()
     }
 
+    /// Verifies a message from a registered peer against the key pinned for its connection.
+    /// Relayed content is signed by its author rather than the forwarding peer, so only its
+    /// own signature is checked; everything else must come from the pinned key.
+    fn verify_peer_message(&self, msg: &SentinelMessage, peer_id: &str) -> Result<()> {
+        let pinned = self.peers.get(peer_id).map(|peer| peer.public_key.clone());
+        match pinned {
+            Some(key) if msg.public_key != key && msg.content.is_relayable() => handshake::verify_signature(msg),
+            Some(key) => handshake::verify_envelope(msg, &key),
+            None => Err(anyhow::anyhow!("No pinned key for {}", peer_id)),
+        }
+    }
+
+    /// Dials the best address book candidates whenever the node is below `target_peers`.
+    /// The address book is persisted, so the first round after a restart reconnects to
+    /// previously known peers.
+    pub async fn start_autodial_service(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.autodial_interval);
+        loop {
+            if !self.tick(&mut interval).await { return; }
+            let missing = self.config.target_peers.saturating_sub(self.peers.len());
+            if missing == 0 {
+                continue;
+            }
+            let own_id = self.identity.node_id();
+            let candidates = self.address_book.candidates(missing, unix_now(), |id| id == own_id || self.peers.contains(id));
+            for entry in candidates {
+                let node = Arc::clone(&self);
+                self.spawn_until_shutdown(async move {
+                    let events = node.event_sender();
+                    let dial = Arc::clone(&node).dial_any(&entry.addresses, Some(entry.node_id.clone()), events);
+                    if dial.await.is_err() {
+                        node.address_book.record_failure(&entry.node_id);
+                    }
+                });
+            }
+        }
+    }
+
     pub async fn start_gossip_service(self: Arc<Self>) {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        if !self.config.gossip {
+            return;
+        }
+        let mut interval = tokio::time::interval(self.config.gossip_interval);
         loop {
-            interval.tick().await;
-            let peer_list: Vec<PeerInfo> = self.peers.iter().filter_map(|e| {
-                e.key().parse().ok().map(|addr| PeerInfo {
-                    node_id: e.value().node_id.clone(),
-                    address: addr,
-                    node_name: e.value().node_name.clone(),
-                    last_seen: 0,
-                })
+            if !self.tick(&mut interval).await { return; }
+            let now = unix_now();
+            let peer_list: Vec<PeerInfo> = self.peers.iter().filter(|e| !e.value().relayed).map(|e| PeerInfo {
+                node_id: e.value().node_id.clone(),
+                address: e.value().listen_addr,
+                node_name: e.value().node_name.clone(),
+                last_seen: now.saturating_sub(e.value().last_seen.elapsed().as_secs()),
+                prekey: Some(e.value().prekey.clone()),
             }).collect();
-            
+
             if !peer_list.is_empty() {
                 let msg = SentinelMessage::new(self.identity.node_id(), MessageContent::PeerDiscovery(peer_list));
-                for entry in self.peers.iter() { self.sign_and_send(&entry.value().tx, msg.clone()); }
+                for entry in self.peers.iter() { self.sign_and_send(&entry.value().queue, msg.clone()); }
             }
         }
     }
 
+    /// Records a message for `print_history`. `Direct` messages are kept without their
+    /// header and ciphertext: we cannot open them again, and a stolen database should not
+    /// hold anything to attack.
     pub fn persist_message(&self, msg: &SentinelMessage) -> Result<()> {
         let tree = self.db.open_tree("messages")?;
-        tree.insert(format!("{}:{}", msg.timestamp, msg.sender), msg.to_bytes())?;
+        let mut record = msg.clone();
+        if let MessageContent::Direct { header, ciphertext, .. } = &mut record.content {
+            header.clear();
+            ciphertext.clear();
+        }
+        tree.insert(format!("{}:{}", msg.timestamp, msg.sender), record.to_bytes())?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Ciphertexts are bound to both endpoints, so a payload cannot be replayed under another header
+fn direct_aad(sender: &str, recipient: &str) -> Vec<u8> {
+    [sender.as_bytes(), recipient.as_bytes()].concat()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The TTL a relayed copy carries, or `None` once the message is out of hops. The TTL is
+/// not signed, so a peer could raise it; anything above `DEFAULT_TTL` counts as `DEFAULT_TTL`.
+fn relay_ttl(ttl: u8) -> Option<u8> {
+    (ttl > 1).then(|| ttl.min(DEFAULT_TTL) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_ttl_is_clamped() {
+        assert_eq!(relay_ttl(255), Some(DEFAULT_TTL - 1));
+        assert_eq!(relay_ttl(DEFAULT_TTL + 1), Some(DEFAULT_TTL - 1));
+        assert_eq!(relay_ttl(DEFAULT_TTL), Some(DEFAULT_TTL - 1));
+        assert_eq!(relay_ttl(2), Some(1));
+        assert_eq!(relay_ttl(1), None);
+        assert_eq!(relay_ttl(0), None);
+    }
+}
@@ -1,13 +1,80 @@
+pub mod address_book;
+pub mod config;
 pub mod engine;
 pub mod discovery;
+pub mod dht;
 pub mod network;
+pub mod peers;
 
-pub use engine::{SentinelNode, PeerState};
+use std::net::SocketAddr;
 
+pub use config::{NodeConfig, NodeConfigBuilder};
+pub use engine::SentinelNode;
+pub use sentinel_transport::handshake;
+pub use peers::{ConnectionHandle, Direction, PeerState, PeerTable, QueuePolicy, SendQueue};
+
+/// What the engine reports to its embedder, for inbound and outbound connections alike
 #[derive(Debug, Clone)]
 pub enum SentinelEvent {
-    PeerConnected { peer_id: String, addr: String },
-    PeerDisconnected { peer_id: String },
+    /// The listener is up and accepting connections
+    Listening { addr: SocketAddr },
+    /// A node completed the handshake and is now a peer
+    PeerConnected { peer_id: String, addr: String, direction: Direction },
+    PeerDisconnected { peer_id: String, reason: DisconnectReason },
+    /// A connection failed TLS or the handshake and was never registered
+    HandshakeFailed { addr: SocketAddr, direction: Direction, error: String },
+    /// A hole punch ordered by the signaler got through; `PeerConnected` follows
+    PunchSucceeded { peer_id: String, addr: SocketAddr },
+    PunchFailed { peer_id: String, addr: SocketAddr, error: String },
+    /// A peer is now reached through `relay`; `PeerConnected` follows
+    Relayed { peer_id: String, relay: SocketAddr },
+    RelayFailed { peer_id: String, relay: SocketAddr, error: String },
     ChatMessage { sender: String, text: String },
+    DirectMessage { sender: String, text: String },
+    /// A direct message addressed to us could not be decrypted
+    DirectMessageFailed { sender: String, error: String },
     SystemLog(String),
-}
\ No newline at end of file
+}
+
+/// Why a peer connection was torn down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer said goodbye with a `Disconnect` message
+    Remote(String),
+    /// The stream ended or failed without a goodbye
+    ConnectionLost,
+    /// Too many pings went unanswered
+    Unresponsive,
+    /// The peer sent something that failed verification
+    ProtocolViolation(String),
+    /// The peer read too slowly and its send queue overflowed
+    SlowConsumer,
+    /// A newer connection to the same node took over
+    Replaced,
+    /// Closed on our side, e.g. at shutdown
+    Local(String),
+}
+
+/// How `SentinelNode::send_direct` handed a message off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Queued on the connection to the recipient
+    Direct,
+    /// The recipient is not connected, so the message was flooded to `peers` peers for mesh
+    /// relay; nothing confirms that it arrives
+    Relayed { peers: usize },
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Remote(reason) => write!(f, "peer disconnected: {}", reason),
+            Self::ConnectionLost => write!(f, "connection lost"),
+            Self::Unresponsive => write!(f, "no response to pings"),
+            Self::ProtocolViolation(e) => write!(f, "protocol violation: {}", e),
+            Self::SlowConsumer => write!(f, "send queue overflowed"),
+            Self::Replaced => write!(f, "replaced by a newer connection"),
+            Self::Local(reason) => write!(f, "{}", reason),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Head start each connection attempt gets before the next address joins (RFC 8305)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Orders addresses for a staggered dial: the first address keeps its place, then the
+/// families alternate, so a broken IPv6 (or IPv4) path costs at most one attempt delay.
+pub fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else { return Vec::new() };
+    let (mut lead, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|a| a.is_ipv6() == first.is_ipv6());
+    lead.reverse();
+    other.reverse();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while !lead.is_empty() || !other.is_empty() {
+        ordered.extend(lead.pop());
+        ordered.extend(other.pop());
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_families_alternate_from_the_preferred_address() {
+        let v4 = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let v6 = |port| SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, 1], port));
+        let ordered = interleave_families(&[v4(1), v4(2), v4(3), v6(4), v6(5)]);
+        assert_eq!(ordered, [v4(1), v6(4), v4(2), v6(5), v4(3)]);
+        assert!(interleave_families(&[]).is_empty());
+    }
+}
@@ -1,2 +1,5 @@
+pub mod eyeballs;
+pub mod punch;
 pub mod socket;
+pub mod stun;
 pub use socket::FighterSocket;
\ No newline at end of file
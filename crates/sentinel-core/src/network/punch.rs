@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+
+use super::socket::FighterSocket;
+
+/// Connection attempts in one punch. Early SYNs are usually dropped by the far NAT until
+/// the peer's own SYN has opened it, so a single attempt rarely gets through.
+pub const PUNCH_ATTEMPTS: u32 = 10;
+/// Spacing of the attempts, which is also how long each one waits for an answer
+pub const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Nanoseconds since the Unix epoch, the clock `PunchCommand` timestamps are given in
+pub fn now_ns() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+/// Time left until `timestamp_ns`; zero once it has passed
+pub fn delay_until(timestamp_ns: u64, now_ns: u64) -> Duration {
+    Duration::from_nanos(timestamp_ns.saturating_sub(now_ns))
+}
+
+/// Both ends of a simultaneous open connected, so neither is the natural TLS client.
+/// The lower node ID takes the server side.
+pub fn accepts_tls(local_id: &str, remote_id: &str) -> bool {
+    local_id < remote_id
+}
+
+/// Connects from `local_port` to `target` in a burst of attempts, returning the first
+/// that succeeds. Every attempt reuses the port, so the peer sees the NAT mapping the
+/// signaler announced, and a SYN crossing the peer's own turns into a simultaneous open.
+pub async fn connect_burst(local_port: u16, target: SocketAddr) -> Result<TcpStream> {
+    let mut ticks = tokio::time::interval(PUNCH_INTERVAL);
+    let mut last_error = None;
+    for _ in 0..PUNCH_ATTEMPTS {
+        ticks.tick().await;
+        let socket = FighterSocket::create_war_ready(FighterSocket::unspecified(&target, local_port))?;
+        match tokio::time::timeout(PUNCH_INTERVAL, FighterSocket::connect(socket, target)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = Some(e),
+            Err(_) => last_error = Some(anyhow!("No answer from {}", target)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("No punch attempt made")))
+        .map_err(|e| e.context(format!("Punch to {} failed after {} attempts", target, PUNCH_ATTEMPTS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punch_timing_and_roles() {
+        assert_eq!(delay_until(5_000_000_000, 4_750_000_000), Duration::from_millis(250));
+        // A command that arrives late starts the burst at once
+        assert_eq!(delay_until(1, 2), Duration::ZERO);
+        assert!(accepts_tls("0a", "ff") && !accepts_tls("ff", "0a"));
+    }
+
+    #[tokio::test]
+    async fn test_burst_from_fixed_port_reaches_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let (dialed, accepted) = tokio::join!(connect_burst(port, target), listener.accept());
+        assert_eq!(dialed.unwrap().local_addr().unwrap().port(), port);
+        assert_eq!(accepted.unwrap().1.port(), port);
+    }
+}
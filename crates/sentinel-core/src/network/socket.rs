@@ -1,47 +1,126 @@
 use socket2::{Socket, Domain, Type, Protocol, SockAddr};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket as StdUdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as StdTcpListener};
 use anyhow::{Result, anyhow};
-use stunclient::StunClient; 
+use tokio::net::TcpStream;
 
 pub struct FighterSocket;
 
 impl FighterSocket {
-    /// Discovers public IP using a blocking task to avoid Future 0.1 compatibility issues
-    pub async fn discover_public_ip(local_port: u16) -> Result<SocketAddr> {
-        let local_udp_addr: SocketAddr = format!("0.0.0.0:{}", local_port).parse()?;
-        let udp_socket = StdUdpSocket::bind(local_udp_addr)?;
-
-        let stun_server = "stun.l.google.com:19302"
-            .to_socket_addrs()?
-            .find(|x| x.is_ipv4())
-            .ok_or_else(|| anyhow!("Failed to resolve STUN server"))?;
-
-        let client = StunClient::new(stun_server);
-        
-        let public_addr = tokio::task::spawn_blocking(move || {
-            client.query_external_address(&udp_socket)
-                .map_err(|e| e.to_string())
-        })
-        .await? 
-        .map_err(|e| anyhow!("STUN query failed: {}", e))?; 
-
-        Ok(public_addr)
+    /// The wildcard address of `target`'s family, to bind a socket that can reach it
+    pub fn unspecified(target: &SocketAddr, port: u16) -> SocketAddr {
+        let ip = match target {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        SocketAddr::new(ip, port)
     }
 
-    /// Creates a socket configured for TCP Simultaneous Open (Hole Punching)
-    pub fn create_war_ready(local_addr: SocketAddr) -> Result<Socket> {
+    fn reusable(local_addr: SocketAddr) -> Result<Socket> {
         let domain = if local_addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
         let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
 
         //  allow multiple sockets to bind to the same port maywheather the face with seies of punch lol
         socket.set_reuse_address(true)?;
-        
+
         #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
 
+        Ok(socket)
+    }
+
+    /// Creates a socket configured for TCP Simultaneous Open (Hole Punching)
+    pub fn create_war_ready(local_addr: SocketAddr) -> Result<Socket> {
+        let socket = Self::reusable(local_addr)?;
         socket.bind(&SockAddr::from(local_addr))?;
         socket.set_nonblocking(true)?;
 
         Ok(socket)
     }
-}
\ No newline at end of file
+
+    /// Connects a socket from `create_war_ready` without blocking the runtime. Fails if the
+    /// connection is refused or reset before it is established.
+    pub async fn connect(socket: Socket, target: SocketAddr) -> Result<TcpStream> {
+        match socket.connect(&target.into()) {
+            Ok(_) => {}
+            Err(e) if Self::in_progress(&e) => {}
+            Err(e) => return Err(anyhow!("Fighter punch failed: {}", e)),
+        }
+
+        let std_stream: std::net::TcpStream = socket.into();
+        let stream = TcpStream::from_std(std_stream)?;
+        stream.writable().await?;
+        if let Some(e) = stream.take_error()? {
+            return Err(anyhow!("Connecting to {} failed: {}", target, e));
+        }
+        Ok(stream)
+    }
+
+    /// Whether a non-blocking `connect` is still underway: `EINPROGRESS` on Unix, whose
+    /// value differs between platforms, and `WSAEWOULDBLOCK` on Windows
+    fn in_progress(e: &std::io::Error) -> bool {
+        #[cfg(unix)]
+        if e.raw_os_error() == Some(libc::EINPROGRESS) {
+            return true;
+        }
+        e.kind() == std::io::ErrorKind::WouldBlock
+    }
+
+    /// Creates the listening socket. It shares its port with the punch sockets above, so
+    /// it cannot refuse a second listener by itself; the port is checked first instead.
+    /// An IPv6 socket also accepts IPv4 (as mapped addresses) unless `v6_only` is set,
+    /// which a node listening on both families does.
+    pub fn create_listener(local_addr: SocketAddr, v6_only: bool) -> Result<StdTcpListener> {
+        Self::ensure_port_free(local_addr, v6_only)?;
+        let socket = Self::reusable(local_addr)?;
+        if local_addr.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.bind(&SockAddr::from(local_addr))?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(socket.into())
+    }
+
+    /// Fails if a socket is already listening on `local_addr`. Binding without
+    /// `SO_REUSEPORT` is refused while anything listens there, even another node's
+    /// port-sharing listener, which the kernel would otherwise hand half our accepts.
+    fn ensure_port_free(local_addr: SocketAddr, v6_only: bool) -> Result<()> {
+        if local_addr.port() == 0 {
+            return Ok(());
+        }
+        let domain = if local_addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+        let probe = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        probe.set_reuse_address(true)?;
+        if local_addr.is_ipv6() {
+            probe.set_only_v6(v6_only)?;
+        }
+        probe.bind(&SockAddr::from(local_addr)).map_err(|e| anyhow!("Port {} is already in use: {}", local_addr.port(), e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listener_port_is_not_shared() {
+        let first = FighterSocket::create_listener("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(FighterSocket::create_listener(addr, false).is_err());
+        // Punch sockets still bind the listen port
+        FighterSocket::create_war_ready(addr).unwrap();
+        drop(first);
+        FighterSocket::create_listener(addr, false).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_completes_after_in_progress() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let socket = FighterSocket::create_war_ready(FighterSocket::unspecified(&target, 0)).unwrap();
+        let (connected, accepted) = tokio::join!(FighterSocket::connect(socket, target), listener.accept());
+        assert_eq!(connected.unwrap().local_addr().unwrap(), accepted.unwrap().1);
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{bail, Context, Result};
+use sentinel_protocol::StunMessage;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use super::socket::FighterSocket;
+
+/// Servers asked unless configured otherwise. Two are needed to tell a symmetric NAT.
+pub const DEFAULT_STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+/// Transmissions of each request. RFC 5389 suggests 7, but a dead server should not hold
+/// up startup for half a minute.
+pub const STUN_ATTEMPTS: u32 = 4;
+/// Wait for the first answer, doubled on every retransmission
+pub const STUN_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The NAT in front of the node, in the classic RFC 3489 terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// The mapped address is our own: no NAT in the way
+    Open,
+    /// One mapping for every destination, and anyone may send to it
+    FullCone,
+    /// One mapping, but only hosts we have sent to may answer
+    RestrictedCone,
+    /// One mapping, but only the exact address and port we sent to may answer. Also
+    /// assumed when no server can test filtering.
+    PortRestrictedCone,
+    /// A fresh mapping per destination, so the address the signaler sees is useless to
+    /// other nodes and hole punching will fail
+    Symmetric,
+}
+
+impl std::fmt::Display for NatType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Open => "open (no NAT)",
+            Self::FullCone => "full cone",
+            Self::RestrictedCone => "restricted cone",
+            Self::PortRestrictedCone => "port-restricted cone",
+            Self::Symmetric => "symmetric",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StunReport {
+    pub public_addr: SocketAddr,
+    pub nat_type: NatType,
+}
+
+/// How a server's answer to a request for a changed source got back to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filtering {
+    FromOtherIp,
+    FromOtherPort,
+    /// The server sent it, but the NAT dropped it
+    Blocked,
+    /// No server could answer from another address
+    Untested,
+}
+
+struct Binding {
+    mapped_addr: SocketAddr,
+    other_addr: Option<SocketAddr>,
+    /// Where the answer came from, which differs from the server for changed requests
+    source: SocketAddr,
+}
+
+/// Learns our public address from `servers` and classifies the NAT in front of us. Two
+/// servers seeing different mappings means a symmetric NAT; a server announcing an
+/// alternate address (RFC 5780) lets us test filtering. The UDP socket is bound to
+/// `local_port` where possible.
+pub async fn probe(servers: &[String], local_port: u16) -> Result<StunReport> {
+    let mut resolved: Vec<SocketAddr> = Vec::new();
+    for server in servers {
+        if let Some(addr) = tokio::net::lookup_host(server.as_str()).await.ok().and_then(|mut addrs| addrs.next()) {
+            resolved.push(addr);
+        }
+    }
+    let first = *resolved.first().context("No STUN server could be resolved")?;
+    // One socket asks every server, so they have to share its family
+    resolved.retain(|addr| addr.is_ipv4() == first.is_ipv4());
+    let socket = match UdpSocket::bind(FighterSocket::unspecified(&first, local_port)).await {
+        Ok(socket) => socket,
+        Err(_) => UdpSocket::bind(FighterSocket::unspecified(&first, 0)).await?,
+    };
+
+    let mut answers = Vec::new();
+    for server in resolved {
+        if answers.len() == 2 {
+            break;
+        }
+        if let Ok(binding) = binding(&socket, server, false, false).await {
+            answers.push((server, binding));
+        }
+    }
+    let (server, answer) = answers.first().context("No STUN server answered")?;
+    let local = SocketAddr::new(local_ip_towards(*server).await?, socket.local_addr()?.port());
+    let mappings: Vec<SocketAddr> = answers.iter().map(|(_, b)| b.mapped_addr).collect();
+
+    let filtering = match answers.iter().find(|(_, b)| b.other_addr.is_some()) {
+        Some((server, _)) if answer.mapped_addr != local => test_filtering(&socket, *server).await,
+        _ => Filtering::Untested,
+    };
+    Ok(StunReport { public_addr: answer.mapped_addr, nat_type: classify(local, &mappings, filtering) })
+}
+
+/// Mappings from several servers, compared with our own address, give the mapping
+/// behaviour; the answer to a changed request gives the filtering
+pub fn classify(local: SocketAddr, mappings: &[SocketAddr], filtering: Filtering) -> NatType {
+    if mappings.first() == Some(&local) {
+        return NatType::Open;
+    }
+    if mappings.windows(2).any(|pair| pair[0] != pair[1]) {
+        return NatType::Symmetric;
+    }
+    match filtering {
+        Filtering::FromOtherIp => NatType::FullCone,
+        Filtering::FromOtherPort => NatType::RestrictedCone,
+        Filtering::Blocked | Filtering::Untested => NatType::PortRestrictedCone,
+    }
+}
+
+async fn test_filtering(socket: &UdpSocket, server: SocketAddr) -> Filtering {
+    // A server with a single IP answers the first request from its other port
+    if let Ok(changed) = binding(socket, server, true, true).await {
+        return match changed.source {
+            source if source.ip() != server.ip() => Filtering::FromOtherIp,
+            source if source != server => Filtering::FromOtherPort,
+            _ => Filtering::Untested,
+        };
+    }
+    match binding(socket, server, false, true).await {
+        Ok(changed) if changed.source != server => Filtering::FromOtherPort,
+        Ok(_) => Filtering::Untested,
+        Err(_) => Filtering::Blocked,
+    }
+}
+
+/// Sends one Binding request, retransmitting with a doubling timeout until a response
+/// with its transaction ID arrives
+async fn binding(socket: &UdpSocket, server: SocketAddr, change_ip: bool, change_port: bool) -> Result<Binding> {
+    let request = StunMessage::binding_request(change_ip, change_port);
+    let bytes = request.encode();
+    let mut timeout = STUN_INITIAL_TIMEOUT;
+    let mut buf = [0u8; 1024];
+
+    for _ in 0..STUN_ATTEMPTS {
+        socket.send_to(&bytes, server).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, source) = received?;
+            // Late answers to earlier requests and stray datagrams are ignored
+            if let Ok(StunMessage::BindingResponse { transaction_id, mapped_addr, other_addr }) = StunMessage::decode(&buf[..len]) {
+                if transaction_id == *request.transaction_id() {
+                    return Ok(Binding { mapped_addr, other_addr, source });
+                }
+            }
+        }
+        timeout *= 2;
+    }
+    bail!("No answer from STUN server {} after {} attempts", server, STUN_ATTEMPTS)
+}
+
+/// The source IP the OS picks towards `server`, to tell whether a mapping is our own
+async fn local_ip_towards(server: SocketAddr) -> Result<IpAddr> {
+    let socket = UdpSocket::bind(FighterSocket::unspecified(&server, 0)).await?;
+    socket.connect(server).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let local: SocketAddr = "192.168.1.5:8443".parse().unwrap();
+        let public: SocketAddr = "203.0.113.9:8443".parse().unwrap();
+        let other: SocketAddr = "203.0.113.9:50123".parse().unwrap();
+        assert_eq!(classify(local, &[local, local], Filtering::Untested), NatType::Open);
+        assert_eq!(classify(local, &[public, other], Filtering::FromOtherIp), NatType::Symmetric);
+        assert_eq!(classify(local, &[public, public], Filtering::FromOtherIp), NatType::FullCone);
+        assert_eq!(classify(local, &[public], Filtering::FromOtherPort), NatType::RestrictedCone);
+        assert_eq!(classify(local, &[public, public], Filtering::Blocked), NatType::PortRestrictedCone);
+    }
+
+    /// Answers Binding requests like the signaler's responder, ignoring the first one
+    async fn lossy_responder() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _ = socket.recv_from(&mut buf).await;
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if let Ok(StunMessage::BindingRequest { transaction_id, .. }) = StunMessage::decode(&buf[..len]) {
+                    let response = StunMessage::BindingResponse { transaction_id, mapped_addr: from, other_addr: None };
+                    let _ = socket.send_to(&response.encode(), from).await;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_probe_retransmits_and_detects_no_nat() {
+        let servers = vec![lossy_responder().await.to_string(), lossy_responder().await.to_string()];
+        let report = probe(&servers, 0).await.unwrap();
+        assert_eq!(report.nat_type, NatType::Open);
+        assert_eq!(report.public_addr.ip(), IpAddr::from([127, 0, 0, 1]));
+    }
+}
@@ -0,0 +1,444 @@
+use dashmap::mapref::one::{Ref, RefMut};
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sentinel_protocol::messages::{SentinelMessage, SignedPreKey};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::DisconnectReason;
+
+/// Which side opened the TCP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Owns the reader and writer tasks of one connection. Closing it stops both; the
+/// writer flushes what is queued and sends the TLS close_notify before it exits.
+#[derive(Clone, Default)]
+pub struct ConnectionHandle {
+    token: CancellationToken,
+    reason: Arc<Mutex<Option<DisconnectReason>>>,
+}
+
+impl ConnectionHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Closes the connection. Only the first reason given is kept.
+    pub fn close(&self, reason: DisconnectReason) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.token.cancel();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once the connection has been closed
+    pub async fn closed(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn reason(&self) -> Option<DisconnectReason> {
+        self.reason.lock().unwrap().clone()
+    }
+}
+
+/// Messages a peer's send queue holds before its overflow policy kicks in
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// What a full send queue does with the next message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// Make room by discarding the oldest queued message
+    #[default]
+    DropOldest,
+    /// Discard the message being sent
+    DropNewest,
+    /// Treat the peer as too slow to keep and close the connection
+    Disconnect,
+}
+
+impl std::str::FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            other => Err(format!("unknown queue policy '{}' (drop-oldest, drop-newest, disconnect)", other)),
+        }
+    }
+}
+
+/// Bounded outbound queue of one connection, drained by its writer task
+pub struct SendQueue {
+    messages: Mutex<VecDeque<SentinelMessage>>,
+    ready: Notify,
+    capacity: usize,
+    policy: QueuePolicy,
+    handle: ConnectionHandle,
+    dropped: AtomicU64,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: QueuePolicy, handle: ConnectionHandle) -> Self {
+        Self {
+            messages: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+            handle,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues `msg` for the writer. Returns false if it was not queued, because the
+    /// connection is closed or the queue is full and the policy rejects it.
+    pub fn push(&self, msg: SentinelMessage) -> bool {
+        if self.handle.is_closed() {
+            return false;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.capacity {
+            match self.policy {
+                QueuePolicy::DropOldest => {
+                    messages.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                QueuePolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                QueuePolicy::Disconnect => {
+                    drop(messages);
+                    self.handle.close(DisconnectReason::SlowConsumer);
+                    return false;
+                }
+            }
+        }
+        messages.push_back(msg);
+        drop(messages);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Waits for the next queued message
+    pub(crate) async fn pop(&self) -> SentinelMessage {
+        loop {
+            if let Some(msg) = self.try_pop() {
+                return msg;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    pub(crate) fn try_pop(&self) -> Option<SentinelMessage> {
+        self.messages.lock().unwrap().pop_front()
+    }
+
+    /// Messages waiting to be written
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Messages discarded because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub struct PeerState {
+    pub queue: Arc<SendQueue>,
+    pub node_id: String,
+    pub node_name: String,
+    pub public_key: Vec<u8>,
+    /// Signed prekey from the handshake, for sessions and gossip
+    pub prekey: SignedPreKey,
+    pub last_seen: Instant,
+    /// Address of the active connection
+    pub addr: String,
+    /// Every address this node has been seen at, including the active one
+    pub addresses: Vec<String>,
+    /// Address the node accepts connections on: the dialed address for outbound
+    /// connections, the remote IP with its announced port for inbound ones
+    pub listen_addr: SocketAddr,
+    pub direction: Direction,
+    /// Reached through a relay: `addr` is the relay's, and `listen_addr` is only a guess
+    /// that is neither gossiped nor recorded
+    pub relayed: bool,
+    /// Smoothed round-trip time from Ping/Pong, once measured
+    pub rtt: Option<Duration>,
+    /// Mean deviation of the round-trip time
+    pub jitter: Duration,
+    /// Consecutive pings that went unanswered
+    pub missed_pongs: u32,
+    /// Nonce and send time of the ping awaiting its pong
+    pub(crate) pending_ping: Option<(u64, Instant)>,
+    pub(crate) conn_id: u64,
+    pub handle: ConnectionHandle,
+}
+
+impl Drop for PeerState {
+    /// A peer leaving the table for any reason takes its connection with it
+    fn drop(&mut self) {
+        self.handle.close(DisconnectReason::Local("Removed from peer table".into()));
+    }
+}
+
+impl PeerState {
+    /// Folds an RTT sample into the smoothed estimate the way TCP does (RFC 6298)
+    pub(crate) fn record_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.jitter = sample / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(sample);
+                self.jitter = (self.jitter * 3 + deviation) / 4;
+                self.rtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+        self.missed_pongs = 0;
+        self.pending_ping = None;
+    }
+}
+
+/// Outcome of registering a freshly authenticated connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+    /// First connection to this node
+    New,
+    /// Took over from an existing connection, which is closed
+    Replaced,
+    /// Lost the tie-break against the existing connection and must be closed
+    Duplicate,
+}
+
+/// Peers indexed by verified node ID, with a secondary index by socket address
+#[derive(Default)]
+pub struct PeerTable {
+    by_id: DashMap<String, PeerState>,
+    by_addr: DashMap<String, String>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<Ref<'_, String, PeerState>> {
+        self.by_id.get(node_id)
+    }
+
+    pub fn get_mut(&self, node_id: &str) -> Option<RefMut<'_, String, PeerState>> {
+        self.by_id.get_mut(node_id)
+    }
+
+    pub fn get_by_addr(&self, addr: &str) -> Option<Ref<'_, String, PeerState>> {
+        let node_id = self.by_addr.get(addr)?.value().clone();
+        self.by_id.get(&node_id)
+    }
+
+    pub fn contains(&self, node_id: &str) -> bool {
+        self.by_id.contains_key(node_id)
+    }
+
+    pub fn contains_addr(&self, addr: &str) -> bool {
+        self.get_by_addr(addr).is_some()
+    }
+
+    pub fn iter(&self) -> dashmap::iter::Iter<'_, String, PeerState> {
+        self.by_id.iter()
+    }
+
+    pub fn iter_mut(&self) -> dashmap::iter::IterMut<'_, String, PeerState> {
+        self.by_id.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
+    /// Registers a connection, resolving duplicates when the node is already connected.
+    /// `local_key` is our own public key, used for the tie-break.
+    pub fn insert(&self, mut state: PeerState, local_key: &[u8]) -> Registration {
+        let node_id = state.node_id.clone();
+        self.by_addr.insert(state.addr.clone(), node_id.clone());
+
+        let mut entry = match self.by_id.entry(node_id) {
+            dashmap::mapref::entry::Entry::Vacant(v) => {
+                state.addresses = vec![state.addr.clone()];
+                v.insert(state);
+                return Registration::New;
+            }
+            dashmap::mapref::entry::Entry::Occupied(o) => o,
+        };
+
+        let existing = entry.get_mut();
+        if !existing.addresses.contains(&state.addr) {
+            existing.addresses.push(state.addr.clone());
+        }
+
+        if !prefer_new(local_key, &state.public_key, existing.direction, state.direction) {
+            return Registration::Duplicate;
+        }
+
+        state.addresses = std::mem::take(&mut existing.addresses);
+        entry.insert(state).handle.close(DisconnectReason::Replaced);
+        Registration::Replaced
+    }
+
+    /// Removes the peer only if `conn_id` is still its active connection, so a
+    /// superseded connection closing cannot evict its replacement.
+    pub fn remove_connection(&self, node_id: &str, conn_id: u64) -> Option<PeerState> {
+        let (_, state) = self.by_id.remove_if(node_id, |_, s| s.conn_id == conn_id)?;
+        self.unindex(&state);
+        Some(state)
+    }
+
+    pub fn remove(&self, node_id: &str) -> Option<PeerState> {
+        let (_, state) = self.by_id.remove(node_id)?;
+        self.unindex(&state);
+        Some(state)
+    }
+
+    pub fn retain(&self, mut keep: impl FnMut(&PeerState) -> bool) {
+        let stale: Vec<String> = self.by_id.iter()
+            .filter(|e| !keep(e.value()))
+            .map(|e| e.key().clone())
+            .collect();
+        for node_id in stale {
+            self.remove(&node_id);
+        }
+    }
+
+    fn unindex(&self, state: &PeerState) {
+        for addr in &state.addresses {
+            self.by_addr.remove_if(addr, |_, id| *id == state.node_id);
+        }
+    }
+}
+
+/// Both ends must agree on which of two simultaneous connections survives: the one dialed
+/// by the node with the lower public key. Between connections in the same direction (a
+/// reconnect, NAT rebinding) the newer one wins.
+fn prefer_new(local_key: &[u8], remote_key: &[u8], existing: Direction, new: Direction) -> bool {
+    if existing == new {
+        return true;
+    }
+    let preferred = if local_key < remote_key { Direction::Outbound } else { Direction::Inbound };
+    new == preferred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(node_id: &str, addr: &str, direction: Direction) -> PeerState {
+        let handle = ConnectionHandle::new();
+        PeerState {
+            queue: Arc::new(SendQueue::new(DEFAULT_QUEUE_CAPACITY, QueuePolicy::default(), handle.clone())),
+            node_id: node_id.to_string(),
+            node_name: String::new(),
+            public_key: vec![2; 32],
+            prekey: SignedPreKey { key: vec![3; 32], created: 0, signature: Vec::new() },
+            last_seen: Instant::now(),
+            addr: addr.to_string(),
+            addresses: Vec::new(),
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+            direction,
+            relayed: false,
+            rtt: None,
+            jitter: Duration::ZERO,
+            missed_pongs: 0,
+            pending_ping: None,
+            conn_id: 0,
+            handle,
+        }
+    }
+
+    fn chat(text: &str) -> SentinelMessage {
+        SentinelMessage::new("me".into(), sentinel_protocol::messages::MessageContent::Chat(text.into()))
+    }
+
+    #[test]
+    fn test_queue_overflow_policies() {
+        let oldest = SendQueue::new(2, QueuePolicy::DropOldest, ConnectionHandle::new());
+        assert!(["a", "b", "c"].iter().all(|t| oldest.push(chat(t))));
+        assert_eq!((oldest.len(), oldest.dropped()), (2, 1));
+        assert!(matches!(oldest.try_pop().unwrap().content, sentinel_protocol::messages::MessageContent::Chat(ref t) if t == "b"));
+
+        let newest = SendQueue::new(1, QueuePolicy::DropNewest, ConnectionHandle::new());
+        assert!(newest.push(chat("a")));
+        assert!(!newest.push(chat("b")));
+        assert_eq!((newest.len(), newest.dropped()), (1, 1));
+
+        let handle = ConnectionHandle::new();
+        let strict = SendQueue::new(1, QueuePolicy::Disconnect, handle.clone());
+        assert!(strict.push(chat("a")));
+        assert!(!strict.push(chat("b")));
+        assert_eq!(handle.reason(), Some(DisconnectReason::SlowConsumer));
+        assert!(!strict.push(chat("c")));
+    }
+
+    #[test]
+    fn test_rtt_smoothing() {
+        let mut peer = peer("node", "a", Direction::Inbound);
+        peer.missed_pongs = 2;
+        peer.pending_ping = Some((1, Instant::now()));
+        peer.record_rtt(Duration::from_millis(80));
+        assert_eq!((peer.rtt, peer.jitter), (Some(Duration::from_millis(80)), Duration::from_millis(40)));
+        assert_eq!((peer.missed_pongs, peer.pending_ping), (0, None));
+
+        peer.record_rtt(Duration::from_millis(160));
+        assert_eq!(peer.rtt, Some(Duration::from_millis(90)));
+        assert_eq!(peer.jitter, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_replaced_connection_is_closed() {
+        let table = PeerTable::new();
+        let old = peer("node", "a", Direction::Inbound);
+        let old_handle = old.handle.clone();
+        assert_eq!(table.insert(old, &[1; 32]), Registration::New);
+        assert_eq!(table.insert(peer("node", "b", Direction::Inbound), &[1; 32]), Registration::Replaced);
+        assert_eq!(old_handle.reason(), Some(DisconnectReason::Replaced));
+
+        let current = table.get("node").unwrap().handle.clone();
+        assert!(!current.is_closed());
+        table.remove("node");
+        assert!(current.is_closed());
+    }
+
+    #[test]
+    fn test_tie_break_is_symmetric() {
+        let (low, high) = ([1u8; 32], [2u8; 32]);
+        // Node `low` holds an inbound link and gets its own outbound one; node `high` sees the mirror image
+        let low_keeps_new = prefer_new(&low, &high, Direction::Inbound, Direction::Outbound);
+        let high_keeps_new = prefer_new(&high, &low, Direction::Outbound, Direction::Inbound);
+        assert!(low_keeps_new);
+        assert!(high_keeps_new);
+
+        // Reversed arrival order still keeps the connection dialed by `low`
+        assert!(!prefer_new(&low, &high, Direction::Outbound, Direction::Inbound));
+        assert!(!prefer_new(&high, &low, Direction::Inbound, Direction::Outbound));
+    }
+}
@@ -0,0 +1,258 @@
+use futures::{SinkExt, StreamExt};
+use sentinel_core::{Delivery, Direction, DisconnectReason, NodeConfig, NodeConfigBuilder, SentinelEvent, SentinelNode};
+use sentinel_crypto::{NodeIdentity, PreKey};
+use sentinel_protocol::{MessageContent, PeerInfo, RelayLimits, SentinelCodec, SentinelMessage, SignalingMessage, SignedPreKey};
+use sentinel_transport::handshake::{self, Hello};
+use sentinel_transport::tls_config::{load_or_generate_node_cert, SENTINEL_SERVER_NAME};
+use sentinel_transport::{Connection, SentinelConnector, TlsTransport};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+type Events = mpsc::UnboundedReceiver<SentinelEvent>;
+
+fn config(dir: &Path, name: &str) -> NodeConfigBuilder {
+    NodeConfig::builder(dir.join(name))
+        .listen_addr("127.0.0.1:0".parse().unwrap())
+        .node_name(name)
+        .mdns(false)
+}
+
+async fn start_node(dir: &Path, name: &str) -> (Arc<SentinelNode>, SocketAddr, Events) {
+    run_node(config(dir, name).build()).await
+}
+
+async fn run_node(config: NodeConfig) -> (Arc<SentinelNode>, SocketAddr, Events) {
+    let (node, _signaler_rx) = SentinelNode::with_config(config).await.unwrap();
+    let node = Arc::new(node);
+    let (tx, mut events) = mpsc::unbounded_channel();
+    tokio::spawn(Arc::clone(&node).run(tx));
+    match next_event(&mut events, |e| matches!(e, SentinelEvent::Listening { .. })).await {
+        SentinelEvent::Listening { addr } => (node, addr, events),
+        _ => unreachable!(),
+    }
+}
+
+/// Skips events until one matches, failing the test after `WAIT`
+async fn next_event(events: &mut Events, wanted: impl Fn(&SentinelEvent) -> bool) -> SentinelEvent {
+    let found = tokio::time::timeout(WAIT, async {
+        while let Some(event) = events.recv().await {
+            if wanted(&event) {
+                return Some(event);
+            }
+        }
+        None
+    });
+    found.await.expect("Timed out waiting for an event").expect("Event channel closed")
+}
+
+/// A bare client that completes TLS and the handshake with `node`, then hands over the
+/// raw stream so the test can send whatever it likes
+async fn raw_client(dir: &Path, identity: &NodeIdentity, node: &SentinelNode, addr: SocketAddr) -> Framed<TlsTransport<TcpStream>, SentinelCodec> {
+    std::fs::create_dir_all(dir).unwrap();
+    let (certs, key) = load_or_generate_node_cert(identity, dir).unwrap();
+    let connector = SentinelConnector::new(certs, key);
+    let node_key = node.identity.public_key_bytes();
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let tls = connector.connect(SENTINEL_SERVER_NAME, tcp, Some(node_key.clone())).await.unwrap();
+    let binding = tls.channel_binding().unwrap();
+
+    let prekey = PreKey::generate(0);
+    let hello = Hello {
+        identity,
+        node_name: "raw",
+        listen_port: 1,
+        prekey: SignedPreKey { key: prekey.public_key().to_vec(), created: 0, signature: prekey.sign(identity) },
+    };
+    let conn = Connection::new(Framed::new(tls, SentinelCodec::new()));
+    let conn = handshake::perform(conn, hello, &node_key, &binding, WAIT).await.unwrap();
+    conn.into_parts().0
+}
+
+fn signed(identity: &NodeIdentity, sender: String, content: MessageContent) -> SentinelMessage {
+    let mut msg = SentinelMessage::new(sender, content);
+    msg.public_key = identity.public_key_bytes();
+    msg.signature = identity.sign(&msg.sig_hash());
+    msg
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unsigned_and_miskeyed_messages_drop_the_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let (node, addr, mut events) = start_node(dir.path(), "node").await;
+
+    // A client that signs properly is heard
+    let client = NodeIdentity::generate();
+    let mut stream = raw_client(&dir.path().join("honest"), &client, &node, addr).await;
+    stream.send(signed(&client, client.node_id(), MessageContent::Chat("hello".into()))).await.unwrap();
+    match next_event(&mut events, |e| matches!(e, SentinelEvent::ChatMessage { .. } | SentinelEvent::PeerDisconnected { .. })).await {
+        SentinelEvent::ChatMessage { sender, text } => assert_eq!((sender, text.as_str()), (client.node_id(), "hello")),
+        other => panic!("Signed chat was not delivered: {other:?}"),
+    }
+
+    for i in 0..3 {
+        let (client, other) = (NodeIdentity::generate(), NodeIdentity::generate());
+        let msg = match i {
+            0 => SentinelMessage::new(client.node_id(), MessageContent::Chat("unsigned".into())),
+            // Relayable content may carry another author's signature, but not under our sender ID
+            1 => signed(&other, client.node_id(), MessageContent::Chat("forged".into())),
+            // Everything else must be signed by the key pinned at the TLS handshake
+            _ => {
+                let contact = PeerInfo { node_id: "ab".repeat(32), address: addr, node_name: String::new(), last_seen: 0, prekey: None };
+                signed(&other, other.node_id(), MessageContent::PeerDiscovery(vec![contact]))
+            }
+        };
+        let mut stream = raw_client(&dir.path().join(format!("client{i}")), &client, &node, addr).await;
+        next_event(&mut events, |e| matches!(e, SentinelEvent::PeerConnected { peer_id, .. } if *peer_id == client.node_id())).await;
+
+        stream.send(msg).await.unwrap();
+        let dropped = next_event(&mut events, |e| matches!(e, SentinelEvent::PeerDisconnected { .. } | SentinelEvent::ChatMessage { .. })).await;
+        match dropped {
+            SentinelEvent::PeerDisconnected { peer_id, reason: DisconnectReason::ProtocolViolation(_) } => assert_eq!(peer_id, client.node_id()),
+            other => panic!("Case {i} was not rejected: {other:?}"),
+        }
+        assert!(node.address_book.get(&"ab".repeat(32)).is_none());
+        assert!(!node.peers.contains(&client.node_id()));
+
+        let closed = tokio::time::timeout(WAIT, async { while let Some(Ok(_)) = stream.next().await {} });
+        closed.await.expect("Connection was left open");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_direct_messages_reach_their_recipient() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, _, mut a_events) = start_node(dir.path(), "a").await;
+    let (b, b_addr, mut b_events) = start_node(dir.path(), "b").await;
+    let (a_id, b_id) = (a.identity.node_id(), b.identity.node_id());
+
+    Arc::clone(&a).dial_peer(b_addr.to_string(), Some(b_id.clone()), a.event_sender()).await.unwrap();
+    next_event(&mut b_events, |e| matches!(e, SentinelEvent::PeerConnected { .. })).await;
+
+    assert_eq!(a.send_direct(&b_id, "hello b").unwrap(), Delivery::Direct);
+    match next_event(&mut b_events, |e| matches!(e, SentinelEvent::DirectMessage { .. } | SentinelEvent::DirectMessageFailed { .. })).await {
+        SentinelEvent::DirectMessage { sender, text } => assert_eq!((sender, text.as_str()), (a_id.clone(), "hello b")),
+        other => panic!("Direct message not delivered: {other:?}"),
+    }
+
+    // The reply goes back over the session the first message started
+    assert_eq!(b.send_direct(&a_id, "hello a").unwrap(), Delivery::Direct);
+    match next_event(&mut a_events, |e| matches!(e, SentinelEvent::DirectMessage { .. } | SentinelEvent::DirectMessageFailed { .. })).await {
+        SentinelEvent::DirectMessage { sender, text } => assert_eq!((sender, text.as_str()), (b_id.clone(), "hello a")),
+        other => panic!("Reply not delivered: {other:?}"),
+    }
+
+    // Without a prekey there is no session to start, so nothing is sent
+    assert!(a.send_direct(&"ab".repeat(32), "unknown").is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_direct_messages_to_unconnected_nodes_are_relayed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, _, _a_events) = start_node(dir.path(), "a").await;
+    let (b, b_addr, mut b_events) = start_node(dir.path(), "b").await;
+    let (c, c_addr, mut c_events) = start_node(dir.path(), "c").await;
+    let (a_id, b_id, c_id) = (a.identity.node_id(), b.identity.node_id(), c.identity.node_id());
+
+    // a and c only meet through b
+    for node in [&a, &c] {
+        Arc::clone(node).dial_peer(b_addr.to_string(), Some(b_id.clone()), node.event_sender()).await.unwrap();
+        next_event(&mut b_events, |e| matches!(e, SentinelEvent::PeerConnected { .. })).await;
+    }
+    let contact = PeerInfo { node_id: c_id.clone(), address: c_addr, node_name: String::new(), last_seen: 0, prekey: Some(c.signed_prekey().unwrap()) };
+    a.dht.update(contact);
+
+    assert_eq!(a.send_direct(&c_id, "hello c").unwrap(), Delivery::Relayed { peers: 1 });
+    match next_event(&mut c_events, |e| matches!(e, SentinelEvent::DirectMessage { .. } | SentinelEvent::DirectMessageFailed { .. })).await {
+        SentinelEvent::DirectMessage { sender, text } => assert_eq!((sender, text.as_str()), (a_id, "hello c")),
+        other => panic!("Relayed message not delivered: {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peer_relays_splice_only_ends_that_asked() {
+    let dir = tempfile::tempdir().unwrap();
+    let (relay, relay_addr, mut relay_events) = run_node(config(dir.path(), "relay").relay(RelayLimits::default()).build()).await;
+    let (a, a_addr, mut a_events) = start_node(dir.path(), "a").await;
+    let (b, _, mut b_events) = start_node(dir.path(), "b").await;
+    let (relay_id, a_id, b_id) = (relay.identity.node_id(), a.identity.node_id(), b.identity.node_id());
+    for node in [&a, &b] {
+        Arc::clone(node).dial_peer(relay_addr.to_string(), Some(relay_id.clone()), node.event_sender()).await.unwrap();
+        next_event(&mut relay_events, |e| matches!(e, SentinelEvent::PeerConnected { .. })).await;
+    }
+    let relayed = |e: &SentinelEvent| matches!(e, SentinelEvent::Relayed { .. } | SentinelEvent::RelayFailed { .. });
+
+    // The relay waits for the other end before handing out tokens
+    a.relay_via(&relay_id, &b_id).unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!a.peers.contains(&b_id));
+    b.relay_via(&relay_id, &a_id).unwrap();
+    for (events, other) in [(&mut a_events, &b_id), (&mut b_events, &a_id)] {
+        match next_event(events, relayed).await {
+            SentinelEvent::Relayed { peer_id, relay } => assert_eq!((&peer_id, relay), (other, relay_addr)),
+            other => panic!("Relay failed: {other:?}"),
+        }
+    }
+
+    // An offer nobody asked for is ignored rather than followed to the sender
+    let client = NodeIdentity::generate();
+    let mut stream = raw_client(&dir.path().join("offerer"), &client, &a, a_addr).await;
+    let offer = SignalingMessage::RelayOffer { peer_id: "cd".repeat(32), token: vec![0; 16] };
+    stream.send(signed(&client, client.node_id(), MessageContent::Signal(offer))).await.unwrap();
+    let followed = tokio::time::timeout(Duration::from_millis(500), next_event(&mut a_events, relayed)).await;
+    assert!(followed.is_err(), "Unsolicited offer was followed: {followed:?}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connection_events_on_both_sides() {
+    let dir = tempfile::tempdir().unwrap();
+    let (a, _, mut a_events) = start_node(dir.path(), "a").await;
+    let (b, b_addr, mut b_events) = start_node(dir.path(), "b").await;
+    let (a_id, b_id) = (a.identity.node_id(), b.identity.node_id());
+    let connected = |e: &SentinelEvent| matches!(e, SentinelEvent::PeerConnected { .. });
+    let disconnected = |e: &SentinelEvent| matches!(e, SentinelEvent::PeerDisconnected { .. });
+
+    Arc::clone(&a).dial_peer(b_addr.to_string(), Some(b_id.clone()), a.event_sender()).await.unwrap();
+    match next_event(&mut a_events, connected).await {
+        SentinelEvent::PeerConnected { peer_id, direction, .. } => assert_eq!((peer_id, direction), (b_id.clone(), Direction::Outbound)),
+        _ => unreachable!(),
+    }
+    match next_event(&mut b_events, connected).await {
+        SentinelEvent::PeerConnected { peer_id, direction, .. } => assert_eq!((peer_id, direction), (a_id.clone(), Direction::Inbound)),
+        _ => unreachable!(),
+    }
+
+    // The side that hangs up reports its own reason, the other side the goodbye it got
+    a.disconnect(&b_id, DisconnectReason::Local("test".into()));
+    match next_event(&mut a_events, disconnected).await {
+        SentinelEvent::PeerDisconnected { peer_id, reason } => assert_eq!((peer_id, reason), (b_id.clone(), DisconnectReason::Local("test".into()))),
+        _ => unreachable!(),
+    }
+    match next_event(&mut b_events, disconnected).await {
+        SentinelEvent::PeerDisconnected { peer_id, reason } => {
+            assert_eq!(peer_id, a_id);
+            assert!(matches!(reason, DisconnectReason::Remote(_)), "Unexpected reason {reason:?}");
+        }
+        _ => unreachable!(),
+    }
+
+    // Shutting a node down disconnects its peers the same way
+    Arc::clone(&a).dial_peer(b_addr.to_string(), Some(b_id.clone()), a.event_sender()).await.unwrap();
+    next_event(&mut a_events, connected).await;
+    next_event(&mut b_events, connected).await;
+    b.shutdown(WAIT).await.unwrap();
+    match next_event(&mut a_events, disconnected).await {
+        SentinelEvent::PeerDisconnected { peer_id, reason } => {
+            assert_eq!(peer_id, b_id);
+            assert!(matches!(reason, DisconnectReason::Remote(_)), "Unexpected reason {reason:?}");
+        }
+        _ => unreachable!(),
+    }
+    assert!(a.peers.is_empty());
+}
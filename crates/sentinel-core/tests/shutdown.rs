@@ -0,0 +1,61 @@
+use sentinel_core::{NodeConfig, SentinelNode};
+use sentinel_protocol::messages::PeerInfo;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+async fn start_node(dir: &std::path::Path, name: &str) -> Arc<SentinelNode> {
+    let config = NodeConfig::builder(dir.join(name))
+        .listen_addr("127.0.0.1:0".parse().unwrap())
+        .node_name(name)
+        .mdns(false)
+        .autodial_interval(Duration::from_millis(10))
+        .build();
+    let (node, _signaler_rx) = SentinelNode::with_config(config).await.unwrap();
+    Arc::new(node)
+}
+
+fn contact(node_id: &str, address: SocketAddr, last_seen: u64) -> PeerInfo {
+    PeerInfo { node_id: node_id.to_string(), address, node_name: String::new(), last_seen, prekey: None }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_shutdown_cancels_dials_in_flight() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let b = start_node(dir.path(), "b").await;
+    let (b_tx, mut b_events) = mpsc::unbounded_channel();
+    tokio::spawn(Arc::clone(&b).run(b_tx));
+    let b_addr = match b_events.recv().await {
+        Some(sentinel_core::SentinelEvent::Listening { addr }) => addr,
+        other => panic!("Expected Listening, got {:?}", other),
+    };
+
+    // Accepts TCP but never answers TLS, so a dial to it stays in flight until dropped
+    let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stalled_addr = stalled.local_addr().unwrap();
+    let (dropped_tx, dropped) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        use tokio::io::AsyncReadExt;
+        let (mut stream, _) = stalled.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        let _ = dropped_tx.send(());
+    });
+
+    let a = start_node(dir.path(), "a").await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    a.address_book.merge(contact(&b.identity.node_id(), b_addr, now), "test", now);
+    a.address_book.merge(contact(&"ab".repeat(32), stalled_addr, now), "test", now);
+
+    tokio::spawn(Arc::clone(&a).start_autodial_service());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    a.shutdown(Duration::from_secs(1)).await.expect("Dials were not cancelled");
+    tokio::time::timeout(Duration::from_millis(500), dropped).await
+        .expect("The stalled dial outlived shutdown").unwrap();
+    // A dial that was finishing its handshake must not register the peer afterwards
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(a.peers.is_empty(), "Peers left after shutdown: {:?}", a.peers.iter().map(|e| e.key().clone()).collect::<Vec<_>>());
+}